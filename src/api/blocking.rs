@@ -0,0 +1,109 @@
+//! Synchronous variant of the API client, for callers not running an async runtime.
+//!
+//! `Client` owns a single-threaded Tokio runtime driving `source::CloudSource`, which logs in on
+//! first call and again whenever the session expires. It must not be used from within an async
+//! runtime, where blocking on a future panics.
+
+use super::Error;
+use crate::model;
+use crate::source::{self, CloudSource, DataSource};
+use chrono::{DateTime, Utc};
+use tokio::runtime::Runtime;
+
+pub struct Client {
+    runtime: Runtime,
+    source: CloudSource,
+}
+
+impl Client {
+    /// Create client of `api` (see `api::api`). Fails if the runtime cannot be started.
+    pub fn new(api: model::Api) -> Result<Client, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| {
+                log::error!("unable to start runtime: {}", e);
+                Error::InternalError
+            })?;
+
+        Ok(Client {
+            runtime,
+            source: CloudSource::new(api),
+        })
+    }
+
+    pub fn stations(&self) -> Result<Vec<model::Station>, Error> {
+        self.runtime.block_on(self.source.stations())
+    }
+
+    pub fn station_real_kpi(
+        &self,
+        station: &model::Station,
+    ) -> Result<Vec<model::StationRealKpi>, Error> {
+        self.runtime.block_on(self.source.station_real_kpi(station))
+    }
+
+    pub fn devices(&self, station: &model::Station) -> Result<Vec<model::Device>, Error> {
+        self.runtime.block_on(self.source.devices(station))
+    }
+
+    pub fn device_real_kpi(
+        &self,
+        device: &model::Device,
+    ) -> Result<Vec<model::DeviceRealKpi>, Error> {
+        self.runtime.block_on(self.source.device_real_kpi(device))
+    }
+
+    pub fn station_kpi_history(
+        &self,
+        station: &model::Station,
+        granularity: model::Granularity,
+        collect_time: DateTime<Utc>,
+    ) -> Result<Vec<model::StationKpiHistory>, Error> {
+        self.runtime.block_on(
+            self.source
+                .station_kpi_history(station, granularity, collect_time),
+        )
+    }
+
+    pub fn alarms(
+        &self,
+        station: &model::Station,
+        begin: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<model::Alarm>, Error> {
+        self.runtime
+            .block_on(self.source.alarms(station, begin, end))
+    }
+
+    pub fn snapshot(&self) -> Result<model::Snapshot, Error> {
+        self.runtime.block_on(source::snapshot(&self.source))
+    }
+
+    /// Close the session, if any. Subsequent calls log in again.
+    pub fn logout(&self) -> Result<(), Error> {
+        self.runtime.block_on(self.source.close())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::cassette::{Mode, Player};
+    use std::sync::Arc;
+
+    #[test]
+    fn replay_snapshot() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/cassette.jsonl");
+
+        let mut api = crate::api::api(None, String::new(), String::new());
+        api.cassette = Mode::Replay(Arc::new(Player::open(path).unwrap()));
+        let client = Client::new(api).unwrap();
+
+        let stations = client.stations().unwrap();
+        assert_eq!("STATION-1", stations[0].code);
+        assert_eq!(2, client.devices(&stations[0]).unwrap().len());
+        client.logout().unwrap();
+    }
+}
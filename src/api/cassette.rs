@@ -0,0 +1,340 @@
+//! Recording of API traffic to JSONL cassettes and replaying it back.
+//!
+//! Recorded interactions never contain credentials or tokens, while station codes, device IDs and
+//! serial numbers are consistently replaced with pseudonyms, so that requests made during replay
+//! (built from pseudonymised responses) match requests recorded in the cassette.
+
+use super::endpoint::Endpoint;
+use super::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const REDACTED: &str = "REDACTED";
+const FIRST_DEVICE_ID: u64 = 1000000000000001;
+
+/// Keys whose values are removed from recorded interactions.
+const REDACTED_KEYS: &[&str] = &[
+    "userName",
+    "systemCode",
+    "xsrfToken",
+    "stationName",
+    "stationAddr",
+    "stationLinkman",
+    "linkmanPho",
+    "devName",
+    "latitude",
+    "longitude",
+];
+
+/// Single request to the API together with its response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub endpoint: String,
+    pub request: Value,
+    pub response: Value,
+}
+
+/// How API calls are carried out.
+#[derive(Debug, Clone, Default)]
+pub enum Mode {
+    /// Calls are sent to the API.
+    #[default]
+    Live,
+    /// Calls are sent to the API and recorded.
+    Record(Arc<Recorder>),
+    /// Calls are answered from a cassette, without reaching the API.
+    Replay(Arc<Player>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Station,
+    Device,
+    Esn,
+}
+
+/// Consistent mapping of identifiers to pseudonyms.
+#[derive(Debug, Default)]
+struct Pseudonyms {
+    stations: HashMap<String, String>,
+    devices: HashMap<String, u64>,
+    esns: HashMap<String, String>,
+}
+
+impl Pseudonyms {
+    fn pseudonym(&mut self, kind: Kind, value: &Value) -> Value {
+        match value {
+            Value::String(s) => Value::String(
+                s.split(',')
+                    .map(|id| self.string(kind, id))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            Value::Number(n) => Value::from(self.device(&n.to_string())),
+            _ => value.to_owned(),
+        }
+    }
+
+    fn string(&mut self, kind: Kind, id: &str) -> String {
+        match kind {
+            Kind::Station => {
+                let next = self.stations.len() + 1;
+                self.stations
+                    .entry(id.to_owned())
+                    .or_insert_with(|| format!("STATION-{}", next))
+                    .to_owned()
+            }
+            Kind::Device => self.device(id).to_string(),
+            Kind::Esn => {
+                let next = self.esns.len() + 1;
+                self.esns
+                    .entry(id.to_owned())
+                    .or_insert_with(|| format!("ESN-{}", next))
+                    .to_owned()
+            }
+        }
+    }
+
+    fn device(&mut self, id: &str) -> u64 {
+        let next = FIRST_DEVICE_ID + self.devices.len() as u64;
+        *self.devices.entry(id.to_owned()).or_insert(next)
+    }
+
+    /// Redact and pseudonymise `value` in place, recursively.
+    fn anonymise(&mut self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    let kind = match key.as_str() {
+                        "stationCode" | "stationCodes" => Some(Kind::Station),
+                        "id" | "devId" | "devIds" => Some(Kind::Device),
+                        "esnCode" | "sn" => Some(Kind::Esn),
+                        _ => None,
+                    };
+
+                    if REDACTED_KEYS.contains(&key.as_str()) {
+                        *value = match value {
+                            Value::Number(_) => Value::from(0),
+                            Value::Null => Value::Null,
+                            _ => Value::from(REDACTED),
+                        };
+                    } else if let Some(kind) = kind {
+                        *value = self.pseudonym(kind, value);
+                    } else {
+                        self.anonymise(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|v| self.anonymise(v)),
+            _ => (),
+        }
+    }
+}
+
+/// Appends anonymised interactions to a JSONL cassette.
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<File>,
+    pseudonyms: Mutex<Pseudonyms>,
+}
+
+impl Recorder {
+    /// Record to cassette at `path`, replacing existing one, as pseudonyms of previous recording
+    /// would not be consistent with the new one.
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Recorder> {
+        let file = File::create(path)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+            pseudonyms: Mutex::new(Pseudonyms::default()),
+        })
+    }
+
+    /// Anonymise and append interaction with `endpoint` to the cassette. Recording is best-effort,
+    /// failures are only logged.
+    pub fn record(&self, endpoint: &Endpoint, request: Value, response: Value) {
+        let mut interaction = Interaction {
+            endpoint: endpoint.to_owned(),
+            request,
+            response,
+        };
+
+        if let Ok(mut pseudonyms) = self.pseudonyms.lock() {
+            pseudonyms.anonymise(&mut interaction.request);
+            pseudonyms.anonymise(&mut interaction.response);
+        } else {
+            log::warn!("unable to anonymise interaction, not recording");
+            return;
+        }
+
+        let written = serde_json::to_string(&interaction)
+            .map_err(|e| e.to_string())
+            .and_then(|line| {
+                let mut file = self.file.lock().map_err(|e| e.to_string())?;
+                writeln!(file, "{}", line).map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            log::warn!("unable to record interaction with {}: {}", endpoint, e);
+        }
+    }
+}
+
+/// Serves responses recorded in a cassette.
+#[derive(Debug)]
+pub struct Player {
+    /// Recorded responses by endpoint and request, in order of recording.
+    responses: Mutex<HashMap<(String, String), Vec<Value>>>,
+}
+
+impl Player {
+    /// Load cassette from `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Player> {
+        let file = File::open(path)?;
+        let mut interactions = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let interaction = serde_json::from_str::<Interaction>(&line)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            interactions.push(interaction);
+        }
+        Ok(Player::new(interactions))
+    }
+
+    pub fn new(interactions: Vec<Interaction>) -> Player {
+        let mut responses: HashMap<(String, String), Vec<Value>> = HashMap::new();
+        for interaction in interactions {
+            responses
+                .entry((interaction.endpoint, interaction.request.to_string()))
+                .or_default()
+                .push(interaction.response);
+        }
+        Player {
+            responses: Mutex::new(responses),
+        }
+    }
+
+    /// Response to `request` sent to `endpoint`. Matching responses are served in order of
+    /// recording, the last one being repeated once the others are used up.
+    pub fn replay(&self, endpoint: &Endpoint, request: &Value) -> Result<Value, Error> {
+        let mut responses = self.responses.lock().or(Err(Error::InternalError))?;
+        let key = (endpoint.to_owned(), request.to_string());
+
+        match responses.get_mut(&key) {
+            Some(queue) if queue.len() > 1 => Ok(queue.remove(0)),
+            Some(queue) if !queue.is_empty() => Ok(queue[0].to_owned()),
+            _ => Err(Error::ApiError(format!(
+                "No recorded response for {} {}",
+                endpoint, request
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn anonymise_consistently() {
+        let mut pseudonyms = Pseudonyms::default();
+        let mut request = json!({"stationCodes": "NE=123,NE=456", "systemCode": "secret"});
+        let mut response = json!({
+            "data": [
+                {"stationCode": "NE=456", "devName": "inverter", "id": 987, "esnCode": "ABC"},
+                {"stationCode": "NE=123", "latitude": 11.1, "id": 654, "esnCode": null}
+            ],
+            "params": {"devIds": "654,987"}
+        });
+
+        pseudonyms.anonymise(&mut request);
+        pseudonyms.anonymise(&mut response);
+
+        assert_eq!(
+            json!({"stationCodes": "STATION-1,STATION-2", "systemCode": "REDACTED"}),
+            request
+        );
+        assert_eq!(
+            json!({
+                "data": [
+                    {"stationCode": "STATION-2", "devName": "REDACTED", "id": 1000000000000001u64, "esnCode": "ESN-1"},
+                    {"stationCode": "STATION-1", "latitude": 0, "id": 1000000000000002u64, "esnCode": null}
+                ],
+                "params": {"devIds": "1000000000000002,1000000000000001"}
+            }),
+            response
+        );
+    }
+
+    #[test]
+    fn replay_in_order() {
+        let request = json!({"stationCodes": "STATION-1"});
+        let player = Player::new(vec![
+            Interaction {
+                endpoint: String::from("/getStationRealKpi"),
+                request: request.clone(),
+                response: json!(1),
+            },
+            Interaction {
+                endpoint: String::from("/getStationRealKpi"),
+                request: request.clone(),
+                response: json!(2),
+            },
+        ]);
+
+        assert_eq!(1, player.replay("/getStationRealKpi", &request).unwrap());
+        assert_eq!(2, player.replay("/getStationRealKpi", &request).unwrap());
+        assert_eq!(2, player.replay("/getStationRealKpi", &request).unwrap());
+        assert!(player.replay("/getDevList", &request).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_snapshot() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/cassette.jsonl");
+
+        let mut api = crate::api::api(None, String::new(), String::new());
+        api.cassette = Mode::Replay(Arc::new(Player::open(path).unwrap()));
+
+        let logged_in_api = crate::api::login(&api).await.unwrap();
+        let snapshot = crate::source::snapshot(&logged_in_api).await.unwrap();
+
+        let station = &snapshot.stations[0];
+        assert_eq!("STATION-1", station.station.code);
+        assert_eq!(2, station.devices.len());
+        /* Dongle is not parsed, only its numeric items are read */
+        let dongle = station.devices[0].kpi.as_ref().unwrap();
+        assert_eq!(None, dongle.active_power);
+        assert_eq!(Some(&1.0), dongle.data_items.get("run_state"));
+        assert_eq!(
+            Some(crate::units::KiloWatts(2.053)),
+            station.devices[1].kpi.as_ref().unwrap().active_power
+        );
+        /* Sample time is not reported, time of response is not used instead */
+        assert_eq!(None, station.devices[1].kpi.as_ref().unwrap().collected_at);
+    }
+
+    #[tokio::test]
+    async fn replayed_kpi_belong_to_listed_devices() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/cassette.jsonl");
+
+        let mut api = crate::api::api(None, String::new(), String::new());
+        api.cassette = Mode::Replay(Arc::new(Player::open(path).unwrap()));
+
+        let logged_in_api = crate::api::login(&api).await.unwrap();
+        let snapshot = crate::source::snapshot(&logged_in_api).await.unwrap();
+
+        for device in &snapshot.stations[0].devices {
+            assert_eq!(device.device.id, device.kpi.as_ref().unwrap().id);
+        }
+    }
+}
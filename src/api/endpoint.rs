@@ -1,8 +1,28 @@
 pub type Endpoint = str;
 
 pub const LOGIN: &Endpoint = "/login";
-// pub const LOGOUT: &Endpoint = "/logout";
+pub const LOGOUT: &Endpoint = "/logout";
 pub const STATIONS: &Endpoint = "/getStationList";
 pub const STATION_REAL_KPI: &Endpoint = "/getStationRealKpi";
 pub const DEVICES: &Endpoint = "/getDevList";
 pub const DEVICE_REAL_KPI: &Endpoint = "/getDevRealKpi";
+pub const STATION_KPI_HOUR: &Endpoint = "/getKpiStationHour";
+pub const STATION_KPI_DAY: &Endpoint = "/getKpiStationDay";
+pub const STATION_KPI_MONTH: &Endpoint = "/getKpiStationMonth";
+pub const STATION_KPI_YEAR: &Endpoint = "/getKpiStationYear";
+pub const ALARMS: &Endpoint = "/getAlarmList";
+
+/// All endpoints used by the client.
+pub const ALL: &[&Endpoint] = &[
+    LOGIN,
+    LOGOUT,
+    STATIONS,
+    STATION_REAL_KPI,
+    DEVICES,
+    DEVICE_REAL_KPI,
+    STATION_KPI_HOUR,
+    STATION_KPI_DAY,
+    STATION_KPI_MONTH,
+    STATION_KPI_YEAR,
+    ALARMS,
+];
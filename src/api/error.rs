@@ -1,50 +1,124 @@
-use rocket::http::{ContentType, Status};
-use rocket::request::Request;
-use rocket::response::{self, Responder, Response};
-use std::io::Cursor;
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+
+/// Broad category of `Error`, e.g. for deciding whether to retry or how to report it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Credentials were rejected or session is no longer valid.
+    Authentication,
+    /// API quota was exceeded.
+    RateLimited,
+    /// API could not be reached or reported failure.
+    Api,
+    /// API responded with data which could not be understood.
+    InvalidResponse,
+    /// Device type is not supported.
+    Unsupported,
+    /// Failure within the client itself.
+    Internal,
+}
+
+impl ErrorKind {
+    /// Name of the kind, e.g. for metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Authentication => "authentication",
+            ErrorKind::RateLimited => "rate_limited",
+            ErrorKind::Api => "api",
+            ErrorKind::InvalidResponse => "invalid_response",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::Internal => "internal",
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Error {
     LoginError(String),
+    SessionExpired(String),
     ApiError(String),
-    UnexpectedApiResponse,
-    InvalidResponse(String, String),
+    /// Request could not be sent or its response could not be read.
+    RequestError(Arc<reqwest::Error>),
+    /// Response is valid JSON, but not of expected structure.
+    UnexpectedApiResponse(Arc<serde_json::Error>),
+    /// Response (first field) is not valid JSON.
+    InvalidResponse(String, Arc<serde_json::Error>),
     UnknownDeviceType(u64),
+    /// Call is not supported by the data source.
+    NotSupported(String),
     RateExceeded(String),
+    /// Device could not be reached over Modbus or rejected the request.
+    ModbusError(String),
     FormatError,
     InternalError,
 }
 
-impl<'r> Responder<'r, 'static> for Error {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
         match self {
-            Error::RateExceeded(s) => {
-                let error = format!("<html><body><h3>429 Too Many Requests</h3>Downsteram API response: <code>{}</code></body></html>", s);
-                Response::build()
-                    .status(Status::TooManyRequests)
-                    .sized_body(error.len(), Cursor::new(error))
-                    .header(ContentType::new("text", "html"))
-                    .ok()
-            }
-            Error::LoginError(s) => {
-                let error = format!("<html><body><h3>403 Forbidden</h3>Error white authenticating to downstream API: <code>{}</code></body></html>", s);
-                Response::build()
-                    .status(Status::Forbidden)
-                    .sized_body(error.len(), Cursor::new(error))
-                    .header(ContentType::new("text", "html"))
-                    .ok()
-            }
-            _ => {
-                let error = format!(
-                    "<html><body><h3>Unknown exception</h3><code>{:?}</code></body></html>",
-                    self
-                );
-                Response::build()
-                    .status(Status::InternalServerError)
-                    .sized_body(error.len(), Cursor::new(error))
-                    .header(ContentType::new("text", "html"))
-                    .ok()
+            Error::LoginError(_) | Error::SessionExpired(_) => ErrorKind::Authentication,
+            Error::RateExceeded(_) => ErrorKind::RateLimited,
+            Error::ApiError(_) | Error::RequestError(_) | Error::ModbusError(_) => ErrorKind::Api,
+            Error::UnexpectedApiResponse(_) | Error::InvalidResponse(_, _) => {
+                ErrorKind::InvalidResponse
             }
+            Error::UnknownDeviceType(_) | Error::NotSupported(_) => ErrorKind::Unsupported,
+            Error::FormatError | Error::InternalError => ErrorKind::Internal,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::LoginError(s) => write!(f, "login failed: {}", s),
+            Error::SessionExpired(s) => write!(f, "session expired: {}", s),
+            Error::ApiError(s) => write!(f, "API error: {}", s),
+            Error::RequestError(e) => write!(f, "API request failed: {}", e),
+            Error::UnexpectedApiResponse(e) => write!(f, "unexpected API response: {}", e),
+            Error::InvalidResponse(s, e) => write!(f, "invalid API response ({}): {}", e, s),
+            Error::UnknownDeviceType(id) => write!(f, "unsupported device type {}", id),
+            Error::NotSupported(s) => write!(f, "not supported by data source: {}", s),
+            Error::RateExceeded(s) => write!(f, "API rate exceeded: {}", s),
+            Error::ModbusError(s) => write!(f, "Modbus request failed: {}", s),
+            Error::FormatError => write!(f, "unable to format metrics"),
+            Error::InternalError => write!(f, "internal error"),
         }
     }
 }
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::RequestError(e) => Some(e.as_ref()),
+            Error::UnexpectedApiResponse(e) | Error::InvalidResponse(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Error {
+        Error::RequestError(Arc::new(error))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn source_chain() {
+        let json_error = serde_json::from_str::<u64>("\"foo\"").unwrap_err();
+        let message = json_error.to_string();
+        let error = Error::UnexpectedApiResponse(Arc::new(json_error));
+
+        assert_eq!(ErrorKind::InvalidResponse, error.kind());
+        assert_eq!(
+            Some(message),
+            error.source().map(|source| source.to_string())
+        );
+        assert!(error.to_string().starts_with("unexpected API response"));
+    }
+}
@@ -0,0 +1,144 @@
+//! Client of FusionSolar kiosk mode, i.e. public share link of a station.
+//!
+//! Kiosk does not require Northbound API credentials, only the token (`kk` parameter of the share
+//! link), but provides overview of single station only, without devices.
+
+use super::response::station_kiosk_file::{StationKioskFile, StationKioskFileResponse};
+use super::Error;
+use crate::model;
+use crate::units::{KiloWattHours, KiloWatts};
+use chrono::Utc;
+use std::sync::Arc;
+
+pub const KIOSK_URL: &str =
+    "https://region01eu5.fusionsolar.huawei.com/rest/pvms/web/kiosk/v1/station-kiosk-file";
+
+/// Station code used if kiosk does not report one.
+const DEFAULT_STATION_CODE: &str = "kiosk";
+
+/// Create kiosk client for `token`. If `kiosk_url` is `None`, `KIOSK_URL` is used.
+pub fn kiosk(kiosk_url: Option<String>, token: String) -> model::Kiosk {
+    model::Kiosk {
+        kiosk_url: kiosk_url.unwrap_or_else(|| KIOSK_URL.to_owned()),
+        token,
+        client: reqwest::Client::new(),
+        time_zones: model::TimeZones::default(),
+    }
+}
+
+/// Revert HTML escaping of kiosk data.
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Map kiosk `response` to snapshot of its station.
+fn parse(
+    kiosk: &model::Kiosk,
+    response: StationKioskFileResponse,
+) -> Result<model::Snapshot, Error> {
+    let data = match response {
+        StationKioskFileResponse {
+            success: true,
+            data: Some(data),
+            ..
+        } => unescape(&data),
+        StationKioskFileResponse { fail_code, .. } => {
+            return Err(Error::ApiError(format!("Kiosk failure: {}", fail_code)))
+        }
+    };
+    let file = serde_json::from_str::<StationKioskFile>(&data)
+        .map_err(|e| Error::InvalidResponse(data.to_owned(), Arc::new(e)))?;
+    let collected_at = Utc::now();
+
+    let code = file
+        .station_overview
+        .station_dn
+        .unwrap_or_else(|| DEFAULT_STATION_CODE.to_owned());
+    let station = model::Station {
+        capacity: KiloWatts(file.station_overview.capacity.unwrap_or_default()),
+        name: file.station_overview.station_name,
+        time_zone: kiosk.time_zones.of(&code),
+        code: code.to_owned(),
+    };
+    let kpi = model::StationRealKpi {
+        code,
+        day_power: KiloWattHours(file.real_kpi.daily_energy.unwrap_or_default()),
+        month_power: file.real_kpi.month_energy.map(KiloWattHours),
+        year_power: file.real_kpi.year_energy.map(KiloWattHours),
+        total_power: file.real_kpi.cumulative_energy.map(KiloWattHours),
+        realtime_power: file.real_kpi.real_time_power.map(KiloWatts),
+        social_contribution: file
+            .social_contribution
+            .map(|social| model::SocialContribution {
+                co2_reduction: social.co2_reduction,
+                standard_coal_savings: social.standard_coal_savings,
+                equivalent_tree_planting: social.equivalent_tree_planting,
+            }),
+        collected_at: Some(collected_at),
+    };
+
+    Ok(model::Snapshot {
+        collected_at,
+        stations: vec![model::StationSnapshot {
+            station,
+            kpi: Some(kpi),
+            devices: Vec::new(),
+        }],
+    })
+}
+
+/// Read overview of the kiosk station.
+pub async fn snapshot(kiosk: &model::Kiosk) -> Result<model::Snapshot, Error> {
+    let text = kiosk
+        .client
+        .get(&kiosk.kiosk_url)
+        .query(&[("kk", &kiosk.token)])
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let response = serde_json::from_str::<StationKioskFileResponse>(&text)
+        .map_err(|e| Error::InvalidResponse(text.to_owned(), Arc::new(e)))?;
+
+    parse(kiosk, response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_station_kiosk_file() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/kiosk.json");
+        let input = std::fs::read_to_string(path).unwrap();
+        let response = serde_json::from_str(&input).unwrap();
+
+        let snapshot = parse(&kiosk(None, String::from("token")), response).unwrap();
+        let station = &snapshot.stations[0];
+        let kpi = station.kpi.as_ref().unwrap();
+
+        assert_eq!("NE=12345678", station.station.code);
+        assert_eq!("StationName", station.station.name);
+        assert_eq!(KiloWatts(5.0), station.station.capacity);
+        assert_eq!(KiloWattHours(3.11), kpi.day_power);
+        assert_eq!(Some(KiloWattHours(767.04)), kpi.total_power);
+        assert_eq!(Some(KiloWatts(2.053)), kpi.realtime_power);
+        assert_eq!(
+            Some(0.76),
+            kpi.social_contribution.as_ref().unwrap().co2_reduction
+        );
+    }
+
+    #[test]
+    fn parse_failure() {
+        let response = serde_json::from_str(r#"{"success": false, "failCode": 1}"#).unwrap();
+        assert!(parse(&kiosk(None, String::new()), response).is_err());
+    }
+}
@@ -1,38 +1,56 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cassette;
 pub mod endpoint;
 pub mod error;
+pub mod kiosk;
+pub mod rate_limit;
+pub mod region;
 pub mod response;
+pub mod stats;
+pub mod watch;
 
 use crate::model;
-pub use error::Error;
+use crate::units::{Amperes, Celsius, Hertz, KiloVars, KiloWattHours, KiloWatts, MegaWatts, Volts};
+use chrono::{DateTime, Utc};
+pub use error::{Error, ErrorKind};
+pub use rate_limit::RateLimiter;
 use reqwest::Response;
+use response::get_alarm_list::GetAlarmList;
 use response::get_device_list::GetDevicesList;
 use response::get_device_real_kpi;
+use response::get_station_kpi_history::GetStationKpiHistory;
 use response::get_station_real_kpi::GetStationRealKpi;
 use response::get_stations_list::GetStationsList;
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::{json, Value};
+pub use stats::Stats;
+pub use watch::watch;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 const XSRF_TOKEN: &str = "XSRF-TOKEN";
 
-pub fn api(api_url: String, username: String, password: String) -> model::Api {
+/// Create API client. If `api_url` is `None`, region serving the account is discovered on login.
+pub fn api(api_url: Option<String>, username: String, password: String) -> model::Api {
     model::Api {
         api_url,
         username,
         password,
+        discovered_api_url: Arc::default(),
+        pending_api_urls: Arc::default(),
+        rate_limiter: Arc::new(RateLimiter::new()),
+        stats: Arc::new(Stats::new()),
+        time_zones: model::TimeZones::default(),
+        cassette: cassette::Mode::Live,
     }
 }
 
-fn extract_xsrf_token(response: Response) -> Result<String, Error> {
+fn extract_xsrf_token(response: &Response) -> Option<String> {
     response
         .cookies()
         .find(|cookie| cookie.name() == XSRF_TOKEN)
-        .ok_or_else(|| {
-            Error::LoginError(format!(
-                "No XSRF-TOKEN received (server responded {})",
-                response.status()
-            ))
-        })
         .map(|cookie| String::from(cookie.value()))
 }
 
@@ -41,7 +59,7 @@ fn map_api_err(error: reqwest::Error) -> Error {
     match error.status() {
         Some(http::StatusCode::TOO_MANY_REQUESTS) => Error::RateExceeded(error.to_string()),
         Some(http::StatusCode::UNAUTHORIZED) => Error::LoginError(error.to_string()),
-        _ => Error::ApiError(error.to_string()),
+        _ => Error::from(error),
     }
 }
 
@@ -64,41 +82,186 @@ fn map_response_status(value: Value) -> Result<Value, Error> {
             Some(response::FailCode::AccessFrequencyIsTooHigh) => {
                 Err(Error::RateExceeded(value.to_string()))
             }
+            /* {"data":"USER_MUST_RELOGIN","failCode":305,"params":null,"success":false} */
+            Some(response::FailCode::UserMustRelogin) => {
+                Err(Error::SessionExpired(value.to_string()))
+            }
             _ => Err(Error::ApiError(value.to_string())),
         }
     }
 }
 
-pub async fn login(api: &model::Api) -> Result<model::LoggedInApi, Error> {
+/// Outcome of login attempt against single region.
+enum LoginAttempt {
+    LoggedIn(model::LoggedInApi),
+    /// Region redirected login to API at another URL.
+    Redirected(String),
+    /// Region does not know the account, which may be served by another one.
+    WrongRegion(String),
+}
+
+async fn login_at(api: &model::Api, api_url: &str) -> Result<LoginAttempt, Error> {
     let client = reqwest::ClientBuilder::new()
         .cookie_store(true)
+        .redirect(reqwest::redirect::Policy::none())
         .build()
         .or(Err(Error::InternalError))?;
-    let url = format!("{}{}", api.api_url, endpoint::LOGIN);
+    let url = format!("{}{}", api_url, endpoint::LOGIN);
 
     let request_body = HashMap::from([
         ("userName", api.username.to_owned()),
         ("systemCode", api.password.to_owned()),
     ]);
 
-    client
+    let response = client
         .post(url)
         .json(&request_body)
         .send()
         .await
-        .map_err(map_api_err)
-        .map(extract_xsrf_token)?
-        .map(|token| model::LoggedInApi {
-            api_url: api.api_url.to_owned(),
-            xsrf_token: token,
-            client,
-        })
+        .map_err(map_api_err)?;
+
+    if response.status().is_redirection() {
+        let redirected_api_url = response
+            .headers()
+            .get(http::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| response.url().join(location).ok())
+            .and_then(|url| region::api_url_of(&url))
+            .ok_or_else(|| {
+                Error::LoginError(format!("Invalid redirect from {}", response.url()))
+            })?;
+        return Ok(LoginAttempt::Redirected(redirected_api_url));
+    }
+
+    let token = match extract_xsrf_token(&response) {
+        Some(token) => token,
+        None => {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            let fail_code = serde_json::from_str::<Value>(&body)
+                .ok()
+                .and_then(|value| value.get("failCode").and_then(Value::as_u64));
+            return match fail_code.and_then(num::FromPrimitive::from_u64) {
+                Some(response::FailCode::UserDoesNotExist) => Ok(LoginAttempt::WrongRegion(body)),
+                _ => Err(Error::LoginError(format!(
+                    "No XSRF-TOKEN received (server responded {}: {})",
+                    status, body
+                ))),
+            };
+        }
+    };
+
+    Ok(LoginAttempt::LoggedIn(model::LoggedInApi {
+        api_url: api_url.to_owned(),
+        xsrf_token: token,
+        client,
+        rate_limiter: api.rate_limiter.clone(),
+        stats: api.stats.clone(),
+        time_zones: api.time_zones.clone(),
+        cassette: api.cassette.clone(),
+    }))
+}
+
+/// Request body of login, as recorded in cassettes.
+fn login_request(api: &model::Api) -> Value {
+    json!({"userName": api.username, "systemCode": api.password})
+}
+
+/// Log in to cassette being replayed.
+fn replay_login(api: &model::Api) -> Result<model::LoggedInApi, Error> {
+    Ok(model::LoggedInApi {
+        api_url: String::from("replay"),
+        xsrf_token: String::from(cassette::REDACTED),
+        client: reqwest::Client::new(),
+        rate_limiter: api.rate_limiter.clone(),
+        stats: api.stats.clone(),
+        time_zones: api.time_zones.clone(),
+        cassette: api.cassette.clone(),
+    })
+}
+
+/// Log in to API. Unless API URL is configured, known regions are tried in order until one of
+/// them knows the account, and the successful one is remembered for subsequent logins. Rejected
+/// credentials end the discovery, so that the account does not get locked out. Discovery stopped
+/// by exhausted login quota continues with the regions not tried yet on next login, regions which
+/// cannot be reached are skipped.
+pub async fn login(api: &model::Api) -> Result<model::LoggedInApi, Error> {
+    let result = login_regions(api, region::candidates(api)).await;
+    api.stats.record_request(endpoint::LOGIN, &result);
+    api.stats.record_login(&result);
+    result
+}
+
+async fn login_regions(
+    api: &model::Api,
+    mut candidates: Vec<String>,
+) -> Result<model::LoggedInApi, Error> {
+    if let cassette::Mode::Replay(_) = api.cassette {
+        api.rate_limiter.acquire(endpoint::LOGIN)?;
+        return replay_login(api);
+    }
+
+    let mut i = 0;
+    let mut unreachable = None;
+
+    while let Some(api_url) = candidates.get(i).cloned() {
+        /* Every attempt counts against login quota, wherever it is sent */
+        if let Err(e) = api.rate_limiter.acquire(endpoint::LOGIN) {
+            region::postpone(api, &candidates[i..]);
+            return Err(e);
+        }
+        i += 1;
+        let attempt = match login_at(api, &api_url).await {
+            Err(Error::RequestError(e)) => {
+                log::warn!("unable to reach {}: {}", api_url, e);
+                unreachable = Some(Error::RequestError(e));
+                continue;
+            }
+            attempt => attempt?,
+        };
+        match attempt {
+            LoginAttempt::LoggedIn(logged_in_api) => {
+                region::remember(api, &logged_in_api.api_url);
+                if let cassette::Mode::Record(recorder) = &api.cassette {
+                    let response = json!({ "xsrfToken": cassette::REDACTED });
+                    recorder.record(endpoint::LOGIN, login_request(api), response);
+                }
+                return Ok(logged_in_api);
+            }
+            LoginAttempt::Redirected(redirected_api_url) => {
+                log::debug!("login at {} redirected to {}", api_url, redirected_api_url);
+                if !candidates[..i].contains(&redirected_api_url) {
+                    candidates.insert(i, redirected_api_url);
+                }
+            }
+            LoginAttempt::WrongRegion(response) => {
+                log::debug!("account not known at {}: {}", api_url, response);
+            }
+        }
+    }
+
+    /* Account may be served by region which could not be reached */
+    Err(unreachable
+        .unwrap_or_else(|| Error::LoginError(String::from("Account not known to any region"))))
+}
+
+/// Close session of `api`, so it does not count against account session limits.
+pub async fn logout(api: model::LoggedInApi) -> Result<(), Error> {
+    if let cassette::Mode::Replay(_) = api.cassette {
+        return Ok(());
+    }
+    let request_body = HashMap::from([("xsrfToken", api.xsrf_token.to_owned())]);
+
+    post(&api, endpoint::LOGOUT, Some(&request_body))
+        .await
+        .map(|_| ())
 }
 
-async fn post(
+/// Send request to the API and parse its JSON response.
+async fn send<T: Serialize>(
     api: &model::LoggedInApi,
     endpoint: &endpoint::Endpoint,
-    data: Option<&HashMap<&str, String>>,
+    data: Option<&T>,
 ) -> Result<Value, Error> {
     let url = format!("{}{}", api.api_url, endpoint);
 
@@ -114,29 +277,64 @@ async fn post(
         .map_err(map_api_err)
         .map(|r| r.text())?
         .await
-        .map_err(|e| Error::ApiError(format!("Error reading API response: {}", e)))
+        .map_err(Error::from)
         .map(|s| {
-            serde_json::from_str::<Value>(&s).map_err(|e| Error::InvalidResponse(s, e.to_string()))
+            serde_json::from_str::<Value>(&s).map_err(|e| Error::InvalidResponse(s, Arc::new(e)))
         })?
-        .map(map_response_status)?
+}
+
+async fn post<T: Serialize>(
+    api: &model::LoggedInApi,
+    endpoint: &endpoint::Endpoint,
+    data: Option<&T>,
+) -> Result<Value, Error> {
+    let result = call(api, endpoint, data).await;
+    api.stats.record_request(endpoint, &result);
+    result
+}
+
+/// Carry out call of `endpoint` according to cassette mode.
+async fn call<T: Serialize>(
+    api: &model::LoggedInApi,
+    endpoint: &endpoint::Endpoint,
+    data: Option<&T>,
+) -> Result<Value, Error> {
+    api.rate_limiter
+        .acquire_within(endpoint, rate_limit::MAX_WAIT)
+        .await?;
+    let request = serde_json::to_value(data).or(Err(Error::InternalError))?;
+
+    let value = match &api.cassette {
+        cassette::Mode::Live => send(api, endpoint, data).await?,
+        cassette::Mode::Record(recorder) => {
+            let value = send(api, endpoint, data).await?;
+            recorder.record(endpoint, request, value.clone());
+            value
+        }
+        cassette::Mode::Replay(player) => player.replay(endpoint, &request)?,
+    };
+
+    map_response_status(value)
 }
 
 pub async fn stations(api: &model::LoggedInApi) -> Result<Vec<model::Station>, Error> {
-    post(api, endpoint::STATIONS, None)
+    post::<Value>(api, endpoint::STATIONS, None)
         .await
         .map(serde_json::from_value::<GetStationsList>)?
-        .or(Err(Error::UnexpectedApiResponse))
+        .map_err(|e| Error::UnexpectedApiResponse(Arc::new(e)))
         .map(|response| {
             let stations = response
                 .data
                 .into_iter()
                 .map(|sta_resp| model::Station {
+                    time_zone: api.time_zones.of(&sta_resp.station_code),
                     code: sta_resp.station_code,
                     name: sta_resp.station_name,
-                    /* convert MWh to kWh */
-                    capacity: sta_resp.capacity * 1000.0,
+                    /* installed peak power is reported in MWp */
+                    capacity: KiloWatts::from(MegaWatts(sta_resp.capacity)),
                 })
-                .collect();
+                .collect::<Vec<_>>();
+            api.rate_limiter.set_stations(stations.len());
             Ok(stations)
         })?
 }
@@ -151,14 +349,21 @@ pub async fn station_real_kpi(
     post(api, endpoint::STATION_REAL_KPI, Some(&request_body))
         .await
         .map(serde_json::from_value::<GetStationRealKpi>)?
-        .or(Err(Error::UnexpectedApiResponse))
+        .map_err(|e| Error::UnexpectedApiResponse(Arc::new(e)))
         .map(|response| {
+            let collected_at = response.params.current_time;
             let stations = response
                 .data
                 .into_iter()
                 .map(|resp| model::StationRealKpi {
                     code: resp.station_code,
-                    day_power: resp.data_item_map.day_power,
+                    day_power: KiloWattHours(resp.data_item_map.day_power),
+                    month_power: resp.data_item_map.month_power.map(KiloWattHours),
+                    year_power: None,
+                    total_power: resp.data_item_map.total_power.map(KiloWattHours),
+                    realtime_power: None,
+                    social_contribution: None,
+                    collected_at,
                 })
                 .collect();
             Ok(stations)
@@ -175,7 +380,7 @@ pub async fn devices(
     post(api, endpoint::DEVICES, Some(&request_body))
         .await
         .map(serde_json::from_value::<GetDevicesList>)?
-        .or(Err(Error::UnexpectedApiResponse))
+        .map_err(|e| Error::UnexpectedApiResponse(Arc::new(e)))
         .map(|response| {
             let devices = response
                 .data
@@ -184,50 +389,190 @@ pub async fn devices(
                     type_id: resp.dev_type_id,
                     id: resp.id,
                 })
-                .collect();
+                .collect::<Vec<_>>();
+            api.rate_limiter.set_devices(&station.code, devices.len());
             Ok(devices)
         })?
 }
 
-/// Takes `device: Device` and if `device.type_id` is supported, reads KPI for that device.
+/// Read KPI of `device`. KPIs of supported device types are parsed, of other ones only their
+/// numeric items (`data_items`) are read.
 pub async fn device_real_kpi(
     api: &model::LoggedInApi,
     device: &model::Device,
 ) -> Result<Vec<model::DeviceRealKpi>, Error> {
+    let request_body = HashMap::from([
+        ("devIds", device.id.to_string()),
+        ("devTypeId", device.type_id.to_string()),
+    ]);
+
+    let value = post(api, endpoint::DEVICE_REAL_KPI, Some(&request_body)).await?;
+
     match num::FromPrimitive::from_u64(device.type_id) as Option<model::DeviceTypeId> {
-        Some(type_id) => {
-            let request_body = HashMap::from([
-                ("devIds", device.id.to_string()),
-                ("devTypeId", device.type_id.to_string()),
-            ]);
-
-            let value = post(api, endpoint::DEVICE_REAL_KPI, Some(&request_body))
-                .await
-                .or(Err(Error::UnexpectedApiResponse))?;
-
-            match type_id {
-                model::DeviceTypeId::StringInverter => {
-                    serde_json::from_value::<get_device_real_kpi::StringInverter>(value)
-                        .or(Err(Error::UnexpectedApiResponse))
-                        .map(|response| {
-                            let devices = response
-                                .data
-                                .iter()
-                                .map(|resp| model::DeviceRealKpi {
-                                    id: resp.dev_id,
-                                    temperature: Some(resp.data_item_map.temperature),
-                                    active_power: Some(resp.data_item_map.active_power),
+        Some(model::DeviceTypeId::StringInverter) => {
+            serde_json::from_value::<get_device_real_kpi::StringInverter>(value)
+                .map_err(|e| Error::UnexpectedApiResponse(Arc::new(e)))
+                .map(|response| {
+                    let devices = response
+                        .data
+                        .iter()
+                        .map(|resp| model::DeviceRealKpi {
+                            id: resp.dev_id,
+                            temperature: Some(Celsius(resp.data_item_map.temperature)),
+                            active_power: Some(KiloWatts(resp.data_item_map.active_power)),
+                            /* Time of response is not the time of sample, so it is not used */
+                            collected_at: resp.collect_time,
+                            open_time: resp.data_item_map.open_time,
+                            close_time: resp.data_item_map.close_time,
+                            pv_strings: resp
+                                .data_item_map
+                                .pv_strings()
+                                .into_iter()
+                                .map(|(index, voltage, current)| model::PvString {
+                                    index,
+                                    voltage: Volts(voltage),
+                                    current: Amperes(current),
                                 })
-                                .collect();
-                            Ok(devices)
-                        })?
-                }
-            }
+                                .collect(),
+                            mppts: resp
+                                .data_item_map
+                                .mppt_energy()
+                                .into_iter()
+                                .map(|(index, energy)| model::Mppt {
+                                    index,
+                                    total_energy: KiloWattHours(energy),
+                                })
+                                .collect(),
+                            phases: resp
+                                .data_item_map
+                                .phases()
+                                .into_iter()
+                                .map(|(name, voltage, current)| model::Phase {
+                                    name: name.to_uppercase(),
+                                    voltage: voltage.map(Volts),
+                                    current: current.map(Amperes),
+                                })
+                                .collect(),
+                            line_voltages: resp
+                                .data_item_map
+                                .line_voltages()
+                                .into_iter()
+                                .map(|(name, voltage)| model::LineVoltage {
+                                    name: name.to_uppercase(),
+                                    voltage: Volts(voltage),
+                                })
+                                .collect(),
+                            frequency: resp.data_item_map.number("elec_freq").map(Hertz),
+                            power_factor: resp.data_item_map.number("power_factor"),
+                            reactive_power: resp
+                                .data_item_map
+                                .number("reactive_power")
+                                .map(KiloVars),
+                            day_energy: resp.data_item_map.number("day_cap").map(KiloWattHours),
+                            total_energy: resp.data_item_map.number("total_cap").map(KiloWattHours),
+                            data_items: resp.data_item_map.numbers(),
+                            ..Default::default()
+                        })
+                        .collect();
+                    Ok(devices)
+                })?
         }
-        None => Err(Error::UnknownDeviceType(device.type_id)),
+        None => serde_json::from_value::<get_device_real_kpi::OtherDevice>(value)
+            .map_err(|e| Error::UnexpectedApiResponse(Arc::new(e)))
+            .map(|response| {
+                Ok(response
+                    .data
+                    .iter()
+                    .map(|resp| model::DeviceRealKpi {
+                        id: resp.dev_id,
+                        collected_at: resp.collect_time,
+                        data_items: resp.numbers(),
+                        ..Default::default()
+                    })
+                    .collect())
+            })?,
     }
 }
 
+/// Read historical KPI of `station` with `granularity` for period containing `collect_time`
+/// (e.g. hourly KPI of the day containing `collect_time`).
+pub async fn station_kpi_history(
+    api: &model::LoggedInApi,
+    station: &model::Station,
+    granularity: model::Granularity,
+    collect_time: DateTime<Utc>,
+) -> Result<Vec<model::StationKpiHistory>, Error> {
+    let endpoint = match granularity {
+        model::Granularity::Hour => endpoint::STATION_KPI_HOUR,
+        model::Granularity::Day => endpoint::STATION_KPI_DAY,
+        model::Granularity::Month => endpoint::STATION_KPI_MONTH,
+        model::Granularity::Year => endpoint::STATION_KPI_YEAR,
+    };
+    let request_body = json!({
+        "stationCodes": station.code,
+        "collectTime": collect_time.timestamp_millis(),
+    });
+
+    post(api, endpoint, Some(&request_body))
+        .await
+        .map(serde_json::from_value::<GetStationKpiHistory>)?
+        .map_err(|e| Error::UnexpectedApiResponse(Arc::new(e)))
+        .map(|response| {
+            let history = response
+                .data
+                .into_iter()
+                .map(|resp| model::StationKpiHistory {
+                    code: resp.station_code,
+                    collected_at: resp.collect_time,
+                    inverter_power: resp.data_item_map.inverter_power.map(KiloWattHours),
+                    ongrid_power: resp.data_item_map.ongrid_power.map(KiloWattHours),
+                    use_power: resp.data_item_map.use_power.map(KiloWattHours),
+                })
+                .collect();
+            Ok(history)
+        })?
+}
+
+/// List alarms of `station` raised between `begin` and `end`.
+pub async fn alarms(
+    api: &model::LoggedInApi,
+    station: &model::Station,
+    begin: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<model::Alarm>, Error> {
+    let request_body = json!({
+        "stationCodes": station.code,
+        "beginTime": begin.timestamp_millis(),
+        "endTime": end.timestamp_millis(),
+        "language": "en_UK",
+    });
+
+    post(api, endpoint::ALARMS, Some(&request_body))
+        .await
+        .map(serde_json::from_value::<GetAlarmList>)?
+        .map_err(|e| Error::UnexpectedApiResponse(Arc::new(e)))
+        .map(|response| {
+            let alarms = response
+                .data
+                .into_iter()
+                .map(|resp| model::Alarm {
+                    station_code: resp.station_code,
+                    id: resp.alarm_id,
+                    name: resp.alarm_name,
+                    cause: resp.alarm_cause,
+                    repair_suggestion: resp.repair_suggestion,
+                    device_name: resp.dev_name,
+                    esn_code: resp.esn_code,
+                    device_type_id: resp.dev_type_id,
+                    level: resp.lev,
+                    status: resp.status,
+                    raised_at: resp.raise_time,
+                })
+                .collect();
+            Ok(alarms)
+        })?
+}
+
 /// Dump devices KPI
 ///
 /// Iterate through all stations and all devices within those stations. Collect raw JSON output
@@ -259,7 +604,7 @@ pub async fn dump_devices(api: &model::LoggedInApi) -> Result<HashMap<u64, Value
                             "No dataItemMap returned for device {}: {}: {}",
                             device.type_id,
                             device.id,
-                            response.to_string()
+                            response
                         );
                     }
                 }
@@ -269,3 +614,132 @@ pub async fn dump_devices(api: &model::LoggedInApi) -> Result<HashMap<u64, Value
 
     Ok(dump)
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve `body` (with `headers`) to every request, returning API URL of the server and number
+    /// of requests served.
+    pub(crate) async fn serve(headers: &str, body: &str) -> (String, Arc<AtomicUsize>) {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            headers,
+            body.len(),
+            body
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}/thirdData", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                served.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (api_url, requests)
+    }
+
+    const WRONG_REGION: &str =
+        r#"{"data":"USER_DOES_NOT_EXIST","failCode":20001,"params":null,"success":false}"#;
+    const WRONG_PASSWORD: &str =
+        r#"{"data":"USERNAME_OR_PASSWORD_ERROR","failCode":20002,"params":null,"success":false}"#;
+    const LOGGED_IN: &str = r#"{"success":true}"#;
+    const XSRF_COOKIE: &str = "Set-Cookie: XSRF-TOKEN=token\r\n";
+
+    fn api() -> model::Api {
+        super::api(None, String::from("user"), String::from("pass"))
+    }
+
+    #[tokio::test]
+    async fn login_discovers_region_knowing_account() {
+        let (wrong_url, wrong) = serve("", WRONG_REGION).await;
+        let (right_url, right) = serve(XSRF_COOKIE, LOGGED_IN).await;
+        let api = api();
+
+        let logged_in_api = login_regions(&api, vec![wrong_url, right_url.clone()])
+            .await
+            .unwrap();
+        assert_eq!(right_url, logged_in_api.api_url);
+        assert_eq!("token", logged_in_api.xsrf_token);
+        assert_eq!(Some(right_url), region::discovered(&api));
+        assert_eq!(
+            (1, 1),
+            (wrong.load(Ordering::SeqCst), right.load(Ordering::SeqCst))
+        );
+        assert_eq!(Some(3), api.rate_limiter.remaining(endpoint::LOGIN));
+    }
+
+    #[tokio::test]
+    async fn login_stops_at_rejected_credentials() {
+        let (rejecting_url, _) = serve("", WRONG_PASSWORD).await;
+        let (other_url, other) = serve(XSRF_COOKIE, LOGGED_IN).await;
+        let api = api();
+
+        let result = login_regions(&api, vec![rejecting_url, other_url]).await;
+        assert!(matches!(result, Err(Error::LoginError(_))));
+        assert_eq!(0, other.load(Ordering::SeqCst));
+        assert_eq!(None, region::discovered(&api));
+        assert_eq!(Some(4), api.rate_limiter.remaining(endpoint::LOGIN));
+    }
+
+    #[tokio::test]
+    async fn discovery_continues_on_next_login() {
+        let (wrong_url, wrong) = serve("", WRONG_REGION).await;
+        let (right_url, right) = serve(XSRF_COOKIE, LOGGED_IN).await;
+        let mut api = api();
+        let mut candidates: Vec<String> = (1..region::HOSTS.len())
+            .map(|_| wrong_url.clone())
+            .collect();
+        candidates.push(right_url.clone());
+
+        let result = login_regions(&api, candidates).await;
+        assert!(matches!(result, Err(Error::RateExceeded(_))));
+
+        /* Once quota renews, remaining regions are tried instead of starting over */
+        api.rate_limiter = Arc::new(RateLimiter::new());
+        let logged_in_api = login(&api).await.unwrap();
+        assert_eq!(right_url, logged_in_api.api_url);
+        assert_eq!(
+            (region::HOSTS.len() - 1, 1),
+            (wrong.load(Ordering::SeqCst), right.load(Ordering::SeqCst))
+        );
+        assert_eq!(Some(right_url), region::discovered(&api));
+    }
+
+    #[tokio::test]
+    async fn discovery_skips_unreachable_regions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unreachable_url = format!("http://{}/thirdData", listener.local_addr().unwrap());
+        drop(listener);
+        let (right_url, _) = serve(XSRF_COOKIE, LOGGED_IN).await;
+        let api = api();
+
+        let result = login_regions(&api, vec![unreachable_url.clone()]).await;
+        assert!(matches!(result, Err(Error::RequestError(_))));
+
+        let logged_in_api = login_regions(&api, vec![unreachable_url, right_url.clone()])
+            .await
+            .unwrap();
+        assert_eq!(right_url, logged_in_api.api_url);
+    }
+
+    #[tokio::test]
+    async fn login_attempts_are_limited_by_budget() {
+        let (wrong_url, wrong) = serve("", WRONG_REGION).await;
+        let api = api();
+        let candidates = (0..10).map(|_| wrong_url.clone()).collect();
+
+        let result = login_regions(&api, candidates).await;
+        assert!(matches!(result, Err(Error::RateExceeded(_))));
+        assert_eq!(5, wrong.load(Ordering::SeqCst));
+    }
+}
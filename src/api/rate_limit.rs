@@ -0,0 +1,315 @@
+use super::endpoint::{self, Endpoint};
+use super::Error;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+/* Clock of tokio, so that waiting for budget can be tested with paused time */
+use tokio::time::Instant;
+
+const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
+const TEN_MINUTES: Duration = Duration::from_secs(10 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Describes how call budget of an endpoint grows with size of the account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    /// Budget does not depend on number of stations or devices.
+    Fixed,
+    /// Budget is granted for every station of the account.
+    PerStation,
+    /// Budget is granted for every device of the account.
+    PerDevice,
+}
+
+/// Number of calls allowed within `period`, multiplied according to `scale`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub calls: u32,
+    pub period: Duration,
+    pub scale: Scale,
+}
+
+/// Returns budget enforced by FusionSolar for `endpoint`, if there is any.
+pub fn budget(endpoint: &Endpoint) -> Option<Budget> {
+    let (calls, period, scale) = match endpoint {
+        endpoint::LOGIN => (5, TEN_MINUTES, Scale::Fixed),
+        endpoint::STATIONS => (1, FIVE_MINUTES, Scale::Fixed),
+        endpoint::STATION_REAL_KPI => (1, FIVE_MINUTES, Scale::PerStation),
+        endpoint::DEVICES => (1, FIVE_MINUTES, Scale::PerStation),
+        endpoint::DEVICE_REAL_KPI => (1, FIVE_MINUTES, Scale::PerDevice),
+        endpoint::STATION_KPI_HOUR
+        | endpoint::STATION_KPI_DAY
+        | endpoint::STATION_KPI_MONTH
+        | endpoint::STATION_KPI_YEAR => (24, DAY, Scale::PerStation),
+        endpoint::ALARMS => (1, FIVE_MINUTES, Scale::PerStation),
+        _ => return None,
+    };
+
+    Some(Budget {
+        calls,
+        period,
+        scale,
+    })
+}
+
+/// Shortest interval at which data collected every cycle (station list, realtime KPIs of stations
+/// and devices) can be fetched within the budgets.
+pub fn min_interval() -> Duration {
+    [
+        endpoint::STATIONS,
+        endpoint::STATION_REAL_KPI,
+        endpoint::DEVICES,
+        endpoint::DEVICE_REAL_KPI,
+    ]
+    .iter()
+    .filter_map(|endpoint| budget(endpoint))
+    .map(|budget| budget.period / budget.calls)
+    .max()
+    .unwrap_or_default()
+}
+
+fn exceeded(endpoint: &Endpoint, retry_in: Duration) -> Error {
+    Error::RateExceeded(format!(
+        "client-side budget of calls to {} exhausted, retry in {}s",
+        endpoint,
+        retry_in.as_secs()
+    ))
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Timestamps of calls made within budget period, oldest first.
+    calls: HashMap<String, VecDeque<Instant>>,
+    stations: usize,
+    /// Number of devices reported for every station code.
+    devices: HashMap<String, usize>,
+}
+
+impl State {
+    fn limit(&self, budget: &Budget) -> u32 {
+        let multiplier = match budget.scale {
+            Scale::Fixed => 1,
+            Scale::PerStation => self.stations,
+            Scale::PerDevice => self.devices.values().sum(),
+        };
+        budget.calls * multiplier.max(1) as u32
+    }
+
+    /// Forget calls which are already outside of budget `period`.
+    fn expire(
+        &mut self,
+        endpoint: &Endpoint,
+        period: Duration,
+        now: Instant,
+    ) -> &mut VecDeque<Instant> {
+        let calls = self.calls.entry(endpoint.to_owned()).or_default();
+        while calls
+            .front()
+            .is_some_and(|call| now.duration_since(*call) >= period)
+        {
+            calls.pop_front();
+        }
+        calls
+    }
+}
+
+/// Longest time a call waits for its budget to renew before it is rejected, so that collections
+/// running at the budget period do not fail because of slightly earlier ticks.
+pub const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// Client-side limiter rejecting calls which would exceed FusionSolar per-interface quotas,
+/// instead of waiting for the API to respond with `ACCESS_FREQUENCY_IS_TOO_HIGH`.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    /// Record call to `endpoint` if it fits within its budget, otherwise return
+    /// `Error::RateExceeded` without calling the API.
+    pub fn acquire(&self, endpoint: &Endpoint) -> Result<(), Error> {
+        self.acquire_at(endpoint, Instant::now())
+    }
+
+    /// Record call to `endpoint`, waiting up to `max_wait` for its budget to renew, otherwise
+    /// return `Error::RateExceeded` without calling the API.
+    pub async fn acquire_within(
+        &self,
+        endpoint: &Endpoint,
+        max_wait: Duration,
+    ) -> Result<(), Error> {
+        match self.reserve_at(endpoint, Instant::now())? {
+            Some(retry_in) if retry_in <= max_wait => {
+                log::debug!("waiting {:?} for budget of {}", retry_in, endpoint);
+                tokio::time::sleep(retry_in).await;
+                self.acquire(endpoint)
+            }
+            Some(retry_in) => Err(exceeded(endpoint, retry_in)),
+            None => Ok(()),
+        }
+    }
+
+    fn acquire_at(&self, endpoint: &Endpoint, now: Instant) -> Result<(), Error> {
+        match self.reserve_at(endpoint, now)? {
+            Some(retry_in) => Err(exceeded(endpoint, retry_in)),
+            None => Ok(()),
+        }
+    }
+
+    /// Record call to `endpoint` at `now` if it fits within its budget, otherwise return time
+    /// after which it does.
+    fn reserve_at(&self, endpoint: &Endpoint, now: Instant) -> Result<Option<Duration>, Error> {
+        let budget = match budget(endpoint) {
+            Some(budget) => budget,
+            None => return Ok(None),
+        };
+        let mut state = self.state.lock().or(Err(Error::InternalError))?;
+        let limit = state.limit(&budget) as usize;
+        let calls = state.expire(endpoint, budget.period, now);
+
+        if calls.len() < limit {
+            calls.push_back(now);
+            Ok(None)
+        } else {
+            let retry_in = calls
+                .front()
+                .map(|oldest| budget.period - now.duration_since(*oldest))
+                .unwrap_or_default();
+            Ok(Some(retry_in))
+        }
+    }
+
+    /// Number of calls to `endpoint` still available within current budget period, or `None` if
+    /// the endpoint is not limited.
+    pub fn remaining(&self, endpoint: &Endpoint) -> Option<u32> {
+        self.remaining_at(endpoint, Instant::now())
+    }
+
+    fn remaining_at(&self, endpoint: &Endpoint, now: Instant) -> Option<u32> {
+        let budget = budget(endpoint)?;
+        let mut state = self.state.lock().ok()?;
+        let limit = state.limit(&budget);
+        let used = state.expire(endpoint, budget.period, now).len() as u32;
+        Some(limit.saturating_sub(used))
+    }
+
+    /// Update number of stations, used to scale per-station budgets.
+    pub fn set_stations(&self, count: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.stations = count;
+        }
+    }
+
+    /// Update number of devices installed in station `station_code`, used to scale per-device
+    /// budgets.
+    pub fn set_devices(&self, station_code: &str, count: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.devices.insert(station_code.to_owned(), count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_calls_over_budget() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert!(limiter.acquire_at(endpoint::STATIONS, now).is_ok());
+        assert_eq!(Some(0), limiter.remaining_at(endpoint::STATIONS, now));
+        assert!(matches!(
+            limiter.acquire_at(endpoint::STATIONS, now),
+            Err(Error::RateExceeded(_))
+        ));
+        assert!(limiter
+            .acquire_at(endpoint::STATIONS, now + FIVE_MINUTES)
+            .is_ok());
+    }
+
+    #[test]
+    fn scales_with_account_size() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert_eq!(
+            Some(1),
+            limiter.remaining_at(endpoint::DEVICE_REAL_KPI, now)
+        );
+        limiter.set_devices("StationA", 2);
+        limiter.set_devices("StationB", 1);
+        assert_eq!(
+            Some(3),
+            limiter.remaining_at(endpoint::DEVICE_REAL_KPI, now)
+        );
+
+        limiter.set_stations(2);
+        assert!(limiter.acquire_at(endpoint::STATION_REAL_KPI, now).is_ok());
+        assert_eq!(
+            Some(1),
+            limiter.remaining_at(endpoint::STATION_REAL_KPI, now)
+        );
+    }
+
+    #[test]
+    fn reports_time_until_budget_renews() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert_eq!(None, limiter.reserve_at(endpoint::STATIONS, now).unwrap());
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            limiter
+                .reserve_at(endpoint::STATIONS, now + Duration::from_secs(240))
+                .unwrap()
+        );
+        assert_eq!(FIVE_MINUTES, min_interval());
+    }
+
+    #[tokio::test]
+    async fn waits_for_budget_within_limit() {
+        let limiter = RateLimiter::new();
+
+        assert!(limiter
+            .acquire_within(endpoint::LOGIN, Duration::ZERO)
+            .await
+            .is_ok());
+        limiter
+            .acquire_at(endpoint::STATIONS, Instant::now())
+            .unwrap();
+        assert!(matches!(
+            limiter.acquire_within(endpoint::STATIONS, MAX_WAIT).await,
+            Err(Error::RateExceeded(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_budget_renewed_within_limit() {
+        let limiter = RateLimiter::new();
+        let started_at = Instant::now();
+        let renews_in = Duration::from_secs(10);
+
+        limiter
+            .acquire_at(endpoint::STATIONS, started_at - (FIVE_MINUTES - renews_in))
+            .unwrap();
+        assert!(limiter
+            .acquire_within(endpoint::STATIONS, MAX_WAIT)
+            .await
+            .is_ok());
+        assert!(started_at.elapsed() >= renews_in);
+        assert_eq!(Some(0), limiter.remaining(endpoint::STATIONS));
+    }
+
+    #[test]
+    fn unknown_endpoints_are_not_limited() {
+        let limiter = RateLimiter::new();
+        assert_eq!(None, limiter.remaining("/unknown"));
+        assert!(limiter.acquire("/unknown").is_ok());
+    }
+}
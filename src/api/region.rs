@@ -0,0 +1,138 @@
+use crate::model;
+use reqwest::Url;
+
+const API_PATH: &str = "/thirdData";
+
+/// Known FusionSolar regional hosts, in order in which they are tried during discovery.
+pub const HOSTS: &[&str] = &[
+    "eu5.fusionsolar.huawei.com",
+    "intl.fusionsolar.huawei.com",
+    "la5.fusionsolar.huawei.com",
+    "sg5.fusionsolar.huawei.com",
+    "au5.fusionsolar.huawei.com",
+    "uni001eu5.fusionsolar.huawei.com",
+    "uni002eu5.fusionsolar.huawei.com",
+    "uni003eu5.fusionsolar.huawei.com",
+    "uni004eu5.fusionsolar.huawei.com",
+];
+
+/// Base API URL served by `host`.
+pub fn api_url(host: &str) -> String {
+    format!("https://{}{}", host, API_PATH)
+}
+
+/// Base API URL served by host of `url`, e.g. target of a redirect.
+pub fn api_url_of(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}://{}:{}{}", url.scheme(), host, port, API_PATH),
+        None => format!("{}://{}{}", url.scheme(), host, API_PATH),
+    })
+}
+
+/// API URLs to try logging in to, in order: configured override only if there is one,
+/// otherwise those left by interrupted discovery, or previously discovered region followed by all
+/// known regions.
+pub fn candidates(api: &model::Api) -> Vec<String> {
+    if let Some(api_url) = &api.api_url {
+        return vec![api_url.to_owned()];
+    }
+    let pending = api
+        .pending_api_urls
+        .lock()
+        .map(|mut pending| std::mem::take(&mut *pending))
+        .unwrap_or_default();
+    if !pending.is_empty() {
+        return pending;
+    }
+
+    let mut candidates: Vec<String> = discovered(api).into_iter().collect();
+    for api_url in HOSTS.iter().map(|host| api_url(host)) {
+        if !candidates.contains(&api_url) {
+            candidates.push(api_url);
+        }
+    }
+    candidates
+}
+
+/// API URL of region which accepted last login.
+pub fn discovered(api: &model::Api) -> Option<String> {
+    api.discovered_api_url
+        .lock()
+        .ok()
+        .and_then(|api_url| api_url.to_owned())
+}
+
+/// Remember `api_urls` not tried yet by discovery, so that next login continues with them instead
+/// of trying the same regions again.
+pub fn postpone(api: &model::Api, api_urls: &[String]) {
+    if let Ok(mut pending) = api.pending_api_urls.lock() {
+        *pending = api_urls.to_vec();
+    }
+}
+
+/// Remember `api_url` as region serving account of `api`.
+pub fn remember(api: &model::Api, api_url: &str) {
+    if let Ok(mut discovered) = api.discovered_api_url.lock() {
+        if discovered.as_deref() != Some(api_url) {
+            log::info!("using FusionSolar API at {}", api_url);
+            *discovered = Some(api_url.to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn api(api_url: Option<&str>) -> model::Api {
+        crate::api::api(
+            api_url.map(String::from),
+            String::from("username"),
+            String::from("password"),
+        )
+    }
+
+    #[test]
+    fn api_url_of_redirect() {
+        let url = Url::parse("https://sg5.fusionsolar.huawei.com/thirdData/login").unwrap();
+        assert_eq!(
+            Some(String::from("https://sg5.fusionsolar.huawei.com/thirdData")),
+            api_url_of(&url)
+        );
+
+        let url = Url::parse("http://127.0.0.1:8080/login").unwrap();
+        assert_eq!(
+            Some(String::from("http://127.0.0.1:8080/thirdData")),
+            api_url_of(&url)
+        );
+    }
+
+    #[test]
+    fn override_disables_discovery() {
+        let api = api(Some("http://127.0.0.1:8080/thirdData"));
+        assert_eq!(vec!["http://127.0.0.1:8080/thirdData"], candidates(&api));
+    }
+
+    #[test]
+    fn discovered_region_is_tried_first() {
+        let api = api(None);
+        assert_eq!(HOSTS.len(), candidates(&api).len());
+        assert_eq!(api_url(HOSTS[0]), candidates(&api)[0]);
+
+        remember(&api, &api_url("sg5.fusionsolar.huawei.com"));
+        assert_eq!(HOSTS.len(), candidates(&api).len());
+        assert_eq!(
+            "https://sg5.fusionsolar.huawei.com/thirdData",
+            candidates(&api)[0]
+        );
+    }
+
+    #[test]
+    fn interrupted_discovery_continues() {
+        let api = api(None);
+        postpone(&api, &[api_url(HOSTS[8])]);
+        assert_eq!(vec![api_url(HOSTS[8])], candidates(&api));
+        assert_eq!(HOSTS.len(), candidates(&api).len());
+    }
+}
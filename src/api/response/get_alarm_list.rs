@@ -0,0 +1,25 @@
+use super::epoch_millis;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Data {
+    pub station_code: String,
+    pub alarm_id: u64,
+    pub alarm_name: String,
+    pub alarm_cause: Option<String>,
+    pub repair_suggestion: Option<String>,
+    pub dev_name: Option<String>,
+    pub esn_code: Option<String>,
+    pub dev_type_id: Option<u64>,
+    pub lev: u64,
+    pub status: u64,
+    #[serde(default, deserialize_with = "epoch_millis")]
+    pub raise_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct GetAlarmList {
+    pub data: Vec<Data>,
+}
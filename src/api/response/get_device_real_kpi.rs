@@ -2,24 +2,144 @@ use serde::Deserialize;
 
 /* Device Type 1: String Inverter */
 pub mod string_inverter {
+    use super::super::epoch_millis;
+    use chrono::{DateTime, Utc};
     use serde::Deserialize;
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    /// Maximum number of PV strings of a string inverter.
+    pub const PV_STRINGS: u8 = 24;
+    /// Names of AC phases, prefixing their items (`a_u`, `b_i`, ...).
+    pub const PHASES: [&str; 3] = ["a", "b", "c"];
+    /// Names of pairs of AC phases, prefixing their line voltages (`ab_u`, ...).
+    pub const LINES: [&str; 3] = ["ab", "bc", "ca"];
 
     #[derive(Deserialize)]
     pub struct DataItemMap {
         pub temperature: f64,
         pub active_power: f64,
         pub mppt_power: f64,
+        #[serde(default, deserialize_with = "epoch_millis")]
+        pub open_time: Option<DateTime<Utc>>,
+        #[serde(default, deserialize_with = "epoch_millis")]
+        pub close_time: Option<DateTime<Utc>>,
+        /// Numbered items (`pv1_u`, `mppt_1_cap`, ...) and items not parsed above.
+        #[serde(flatten)]
+        pub other: HashMap<String, Value>,
+    }
+
+    impl DataItemMap {
+        /// Numeric item `key`, if reported (`null` or missing otherwise).
+        pub fn number(&self, key: &str) -> Option<f64> {
+            self.other.get(key).and_then(Value::as_f64)
+        }
+
+        /// Voltage (V) and current (A) of every AC phase reported, by phase name. Phases missing
+        /// both values (e.g. `b` and `c` of single-phase devices) are skipped.
+        pub fn phases(&self) -> Vec<(&'static str, Option<f64>, Option<f64>)> {
+            PHASES
+                .iter()
+                .filter_map(|phase| {
+                    let voltage = self.number(&format!("{}_u", phase));
+                    let current = self.number(&format!("{}_i", phase));
+                    match (voltage, current) {
+                        (None, None) => None,
+                        _ => Some((*phase, voltage, current)),
+                    }
+                })
+                .collect()
+        }
+
+        /// Line voltage (V) between every pair of AC phases reported, by names of the phases.
+        pub fn line_voltages(&self) -> Vec<(&'static str, f64)> {
+            LINES
+                .iter()
+                .filter_map(|line| Some((*line, self.number(&format!("{}_u", line))?)))
+                .collect()
+        }
+
+        /// Voltage (V) and current (A) of every PV string reported, by string number.
+        pub fn pv_strings(&self) -> Vec<(u8, f64, f64)> {
+            (1..=PV_STRINGS)
+                .filter_map(|n| {
+                    let voltage = self.number(&format!("pv{}_u", n))?;
+                    let current = self.number(&format!("pv{}_i", n))?;
+                    Some((n, voltage, current))
+                })
+                .collect()
+        }
+
+        /// All numeric items reported, by key.
+        pub fn numbers(&self) -> HashMap<String, f64> {
+            let parsed = [
+                ("temperature", self.temperature),
+                ("active_power", self.active_power),
+                ("mppt_power", self.mppt_power),
+            ];
+            self.other
+                .iter()
+                .filter_map(|(key, value)| Some((key.to_owned(), value.as_f64()?)))
+                .chain(parsed.iter().map(|(key, value)| (key.to_string(), *value)))
+                .collect()
+        }
+
+        /// Lifetime energy (kWh) of every MPPT reported, by MPPT number.
+        pub fn mppt_energy(&self) -> Vec<(u8, f64)> {
+            (1..=PV_STRINGS)
+                .filter_map(|n| Some((n, self.number(&format!("mppt_{}_cap", n))?)))
+                .collect()
+        }
     }
 
     #[derive(Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Data {
         pub dev_id: u64,
+        #[serde(default, deserialize_with = "epoch_millis")]
+        pub collect_time: Option<DateTime<Utc>>,
         pub data_item_map: DataItemMap,
     }
 }
 
+/* Other device types, of which only numeric items are read */
+pub mod other {
+    use super::super::epoch_millis;
+    use chrono::{DateTime, Utc};
+    use serde::Deserialize;
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Data {
+        pub dev_id: u64,
+        #[serde(default, deserialize_with = "epoch_millis")]
+        pub collect_time: Option<DateTime<Utc>>,
+        pub data_item_map: HashMap<String, Value>,
+    }
+
+    impl Data {
+        /// All numeric items reported, by key.
+        pub fn numbers(&self) -> HashMap<String, f64> {
+            self.data_item_map
+                .iter()
+                .filter_map(|(key, value)| Some((key.to_owned(), value.as_f64()?)))
+                .collect()
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct StringInverter {
     pub data: Vec<string_inverter::Data>,
+    #[serde(default)]
+    pub params: super::Params,
+}
+
+#[derive(Deserialize)]
+pub struct OtherDevice {
+    pub data: Vec<other::Data>,
+    #[serde(default)]
+    pub params: super::Params,
 }
@@ -0,0 +1,25 @@
+use super::epoch_millis;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/* Values are reported as `null` if not available for the station */
+#[derive(Deserialize)]
+pub struct DataItemMap {
+    pub inverter_power: Option<f64>,
+    pub ongrid_power: Option<f64>,
+    pub use_power: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Data {
+    pub station_code: String,
+    #[serde(default, deserialize_with = "epoch_millis")]
+    pub collect_time: Option<DateTime<Utc>>,
+    pub data_item_map: DataItemMap,
+}
+
+#[derive(Deserialize)]
+pub struct GetStationKpiHistory {
+    pub data: Vec<Data>,
+}
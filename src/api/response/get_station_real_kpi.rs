@@ -1,6 +1,8 @@
 #[derive(serde::Deserialize)]
 pub struct DataItemMap {
     pub day_power: f64,
+    pub month_power: Option<f64>,
+    pub total_power: Option<f64>,
 }
 #[derive(serde::Deserialize)]
 pub struct Data {
@@ -12,4 +14,6 @@ pub struct Data {
 #[derive(serde::Deserialize)]
 pub struct GetStationRealKpi {
     pub data: Vec<Data>,
+    #[serde(default)]
+    pub params: super::Params,
 }
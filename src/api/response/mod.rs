@@ -1,18 +1,66 @@
+use chrono::{DateTime, TimeZone, Utc};
 use num_derive::FromPrimitive;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
 
+pub mod get_alarm_list;
 pub mod get_device_list;
 pub mod get_device_real_kpi;
+pub mod get_station_kpi_history;
 pub mod get_station_real_kpi;
 pub mod get_stations_list;
+pub mod station_kiosk_file;
 
 #[derive(FromPrimitive)]
 pub enum FailCode {
+    UserMustRelogin = 305,
     AccessFrequencyIsTooHigh = 407,
+    /// Account is not known to the region, which is how wrong region responds to login.
+    UserDoesNotExist = 20001,
+}
+
+/// Request parameters echoed back by the API.
+#[derive(Deserialize, Default)]
+pub struct Params {
+    /// Time of response (epoch milliseconds).
+    #[serde(rename = "currentTime", default, deserialize_with = "epoch_millis")]
+    pub current_time: Option<DateTime<Utc>>,
+}
+
+/// Convert epoch milliseconds to timestamp.
+pub fn timestamp(millis: f64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis as i64).single()
+}
+
+/// Deserialize optional timestamp sent as epoch milliseconds. Values which are not numbers (e.g.
+/// `"N/A"` reported by some devices) are treated as missing.
+pub fn epoch_millis<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<Value>::deserialize(deserializer)?
+        .as_ref()
+        .and_then(Value::as_f64)
+        .and_then(timestamp))
+}
+
+/// Deserialize optional number sent either as number or numeric string. Other values (e.g. `"--"`
+/// sent by kiosk) are treated as missing.
+pub fn lenient_f64<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.trim().parse().ok(),
+        _ => None,
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use super::get_device_real_kpi::StringInverter;
+    use super::get_device_real_kpi::{string_inverter, OtherDevice, StringInverter};
+    use serde_json::json;
     use std::fs;
     use std::path::PathBuf;
 
@@ -40,11 +88,85 @@ mod test {
         assert_eq!("devName2", output.data[1].dev_name);
     }
 
+    #[test]
+    fn get_device_real_kpi_single_phase() {
+        let input = json!({
+            "temperature": 30.1,
+            "active_power": 1.2,
+            "mppt_power": 1.3,
+            "a_u": 231.5,
+            "a_i": 5.2,
+            "b_u": null,
+            "b_i": null,
+            "ab_u": null,
+        });
+        let output: string_inverter::DataItemMap = serde_json::from_value(input).unwrap();
+        assert_eq!(vec![("a", Some(231.5), Some(5.2))], output.phases());
+        assert!(output.line_voltages().is_empty());
+    }
+
     #[test]
     fn get_device_real_kpi() {
         let input = read_resource("getDeviceRealKpi.json");
         let output: StringInverter = serde_json::from_str(&input).unwrap();
         assert_eq!(2.053, output.data[0].data_item_map.active_power);
+        assert_eq!(24, output.data[0].data_item_map.pv_strings().len());
+        assert_eq!(
+            (1, 334.4, 6.44),
+            output.data[0].data_item_map.pv_strings()[0]
+        );
+        assert_eq!(
+            Some(&(1, 811.73)),
+            output.data[0].data_item_map.mppt_energy().first()
+        );
+        assert_eq!(
+            vec![
+                ("a", Some(220.9), Some(3.058)),
+                ("b", Some(229.8), Some(3.025)),
+                ("c", Some(232.6), Some(3.032))
+            ],
+            output.data[0].data_item_map.phases()
+        );
+        assert_eq!(
+            vec![("ab", 388.6), ("bc", 398.1), ("ca", 396.9)],
+            output.data[0].data_item_map.line_voltages()
+        );
+        let numbers = output.data[0].data_item_map.numbers();
+        assert_eq!(Some(&97.09), numbers.get("efficiency"));
+        assert_eq!(Some(&2.053), numbers.get("active_power"));
+        assert_eq!(
+            Some(1635758600700),
+            output.params.current_time.map(|t| t.timestamp_millis())
+        );
+        assert_eq!(
+            Some(1635745471000),
+            output.data[0]
+                .data_item_map
+                .open_time
+                .map(|t| t.timestamp_millis())
+        );
+    }
+
+    #[test]
+    fn get_kpi_station_day() {
+        let input = read_resource("getKpiStationDay.json");
+        let output: super::get_station_kpi_history::GetStationKpiHistory =
+            serde_json::from_str(&input).unwrap();
+        assert_eq!(Some(3.11), output.data[0].data_item_map.inverter_power);
+        assert_eq!(None, output.data[0].data_item_map.ongrid_power);
+        assert_eq!(
+            Some(1635721200000),
+            output.data[0].collect_time.map(|t| t.timestamp_millis())
+        );
+    }
+
+    #[test]
+    fn get_alarm_list() {
+        let input = read_resource("getAlarmList.json");
+        let output: super::get_alarm_list::GetAlarmList = serde_json::from_str(&input).unwrap();
+        assert_eq!(2011, output.data[0].alarm_id);
+        assert_eq!("String Reverse Connection", output.data[0].alarm_name);
+        assert_eq!(2, output.data[0].lev);
     }
 
     #[test]
@@ -54,6 +176,17 @@ mod test {
         serde_json::from_str::<StringInverter>(&unsupported_type).unwrap();
     }
 
+    #[test]
+    fn get_device_real_kpi_other() {
+        let input = read_resource("getDeviceRealKpi_Unsupported.json");
+        let output: OtherDevice = serde_json::from_str(&input).unwrap();
+        assert_eq!(12313123, output.data[0].dev_id);
+        assert_eq!(
+            Some(&0.0),
+            output.data[0].numbers().get("unsupported-property")
+        );
+    }
+
     #[test]
     #[should_panic]
     fn get_device_real_kpi_valid_json() {
@@ -0,0 +1,56 @@
+use super::lenient_f64;
+use serde::Deserialize;
+
+/// Response envelope, with station data sent as HTML-escaped JSON string.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StationKioskFileResponse {
+    pub success: bool,
+    #[serde(default)]
+    pub fail_code: u64,
+    pub data: Option<String>,
+}
+
+/* Values are sent either as numbers or numeric strings, "--" if not available */
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RealKpi {
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub real_time_power: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub daily_energy: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub month_energy: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub year_energy: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub cumulative_energy: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StationOverview {
+    pub station_name: String,
+    pub station_dn: Option<String>,
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub capacity: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SocialContribution {
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub co2_reduction: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub standard_coal_savings: Option<f64>,
+    #[serde(default, deserialize_with = "lenient_f64")]
+    pub equivalent_tree_planting: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StationKioskFile {
+    pub real_kpi: RealKpi,
+    pub station_overview: StationOverview,
+    pub social_contribution: Option<SocialContribution>,
+}
@@ -0,0 +1,104 @@
+//! Counters of API calls and logins, for monitoring of the client itself.
+
+use super::endpoint::Endpoint;
+use super::{Error, ErrorKind};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Outcome of successful call.
+pub const SUCCESS: &str = "success";
+
+/// Label of call outcome: `SUCCESS` or kind of the error.
+pub fn outcome<T>(result: &Result<T, Error>) -> &'static str {
+    match result {
+        Ok(_) => SUCCESS,
+        Err(e) => e.kind().as_str(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Number of calls by endpoint and outcome.
+    requests: Mutex<HashMap<(String, &'static str), u64>>,
+    /// Number of logins by outcome.
+    logins: Mutex<HashMap<&'static str, u64>>,
+    rate_limited: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Count call of `endpoint` which finished with `result`.
+    pub fn record_request<T>(&self, endpoint: &Endpoint, result: &Result<T, Error>) {
+        if let Ok(mut requests) = self.requests.lock() {
+            *requests
+                .entry((endpoint.to_owned(), outcome(result)))
+                .or_default() += 1;
+        }
+        if let Err(e) = result {
+            if e.kind() == ErrorKind::RateLimited {
+                self.rate_limited.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Count login (including trying all regions) which finished with `result`.
+    pub fn record_login<T>(&self, result: &Result<T, Error>) {
+        if let Ok(mut logins) = self.logins.lock() {
+            *logins.entry(outcome(result)).or_default() += 1;
+        }
+    }
+
+    /// Number of calls by endpoint and outcome.
+    pub fn requests(&self) -> HashMap<(String, &'static str), u64> {
+        self.requests
+            .lock()
+            .map(|requests| requests.clone())
+            .unwrap_or_default()
+    }
+
+    /// Number of logins by outcome.
+    pub fn logins(&self) -> HashMap<&'static str, u64> {
+        self.logins
+            .lock()
+            .map(|logins| logins.clone())
+            .unwrap_or_default()
+    }
+
+    /// Number of calls rejected because of exceeded quota, by the client or the API.
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count_outcomes() {
+        let stats = Stats::new();
+        stats.record_request("/getStationList", &Ok(()));
+        stats.record_request("/getStationList", &Ok(()));
+        stats.record_request::<()>(
+            "/getStationList",
+            &Err(Error::RateExceeded(String::from("quota"))),
+        );
+        stats.record_login::<()>(&Err(Error::LoginError(String::from("denied"))));
+
+        let requests = stats.requests();
+        assert_eq!(
+            Some(&2),
+            requests.get(&(String::from("/getStationList"), SUCCESS))
+        );
+        assert_eq!(
+            Some(&1),
+            requests.get(&(String::from("/getStationList"), "rate_limited"))
+        );
+        assert_eq!(1, stats.rate_limited());
+        assert_eq!(Some(&1), stats.logins().get("authentication"));
+    }
+}
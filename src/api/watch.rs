@@ -0,0 +1,147 @@
+use super::Error;
+use crate::model;
+use futures::stream::{self, Stream};
+use std::time::Duration;
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+struct State {
+    api: model::Api,
+    session: Option<model::LoggedInApi>,
+    interval: Interval,
+}
+
+impl State {
+    /// Collect snapshot, logging in first if there is no session. Session is dropped on errors
+    /// which may have been caused by it, so the next tick logs in again.
+    async fn snapshot(&mut self) -> Result<model::Snapshot, Error> {
+        if self.session.is_none() {
+            self.session = Some(super::login(&self.api).await?);
+        }
+        let session = self.session.as_ref().ok_or(Error::InternalError)?;
+        let result = crate::source::snapshot(session).await;
+
+        match &result {
+            Err(Error::SessionExpired(_))
+            | Err(Error::LoginError(_))
+            | Err(Error::ApiError(_))
+            | Err(Error::RequestError(_)) => {
+                log::debug!(
+                    "dropping API session after error: {:?}",
+                    result.as_ref().err()
+                );
+                self.session = None;
+            }
+            _ => (),
+        }
+        result
+    }
+}
+
+/// Stream of complete plant snapshots, collected from `api` every `interval`, starting
+/// immediately.
+///
+/// API session is established on first tick and reused afterwards; it is re-established after
+/// it expires or the connection fails. Calls are subject to `api.rate_limiter`, so an `interval`
+/// shorter than API quotas allow produces `Error::RateExceeded` items. Errors do not end the
+/// stream.
+pub fn watch(
+    api: model::Api,
+    interval: Duration,
+) -> impl Stream<Item = Result<model::Snapshot, Error>> {
+    let mut interval = time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let state = State {
+        api,
+        session: None,
+        interval,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        state.interval.tick().await;
+        let snapshot = state.snapshot().await;
+        Some((snapshot, state))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::cassette::{Interaction, Mode, Player};
+    use crate::api::{endpoint, stats};
+    use futures::StreamExt;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    /// Interactions of the test cassette, with `prepended` ones served first.
+    fn cassette(prepended: Vec<Interaction>) -> Player {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/cassette.jsonl");
+        let recorded = std::fs::read_to_string(path).unwrap();
+
+        let interactions = prepended
+            .into_iter()
+            .chain(
+                recorded
+                    .lines()
+                    .map(|line| serde_json::from_str::<Interaction>(line).unwrap()),
+            )
+            .collect();
+        Player::new(interactions)
+    }
+
+    fn api(player: Player) -> model::Api {
+        let mut api = crate::api::api(None, String::new(), String::new());
+        api.cassette = Mode::Replay(Arc::new(player));
+        api
+    }
+
+    #[tokio::test]
+    async fn errors_do_not_end_stream() {
+        let api = api(cassette(Vec::new()));
+        let stats = api.stats.clone();
+        let snapshots = watch(api, Duration::from_millis(10));
+        let snapshots: Vec<_> = snapshots.take(3).collect().await;
+
+        let snapshot = snapshots[0].as_ref().unwrap();
+        assert_eq!("STATION-1", snapshot.stations[0].station.code);
+        /* Station list may be fetched once per 5 minutes */
+        assert!(matches!(snapshots[1], Err(Error::RateExceeded(_))));
+        assert!(matches!(snapshots[2], Err(Error::RateExceeded(_))));
+        /* Session is kept after errors not caused by it */
+        assert_eq!(Some(&1), stats.logins().get(stats::SUCCESS));
+    }
+
+    #[tokio::test]
+    async fn expired_session_is_reestablished() {
+        let expired = Interaction {
+            endpoint: endpoint::STATIONS.to_owned(),
+            request: json!(null),
+            response: json!({
+                "data": "USER_MUST_RELOGIN",
+                "failCode": 305,
+                "params": null,
+                "success": false
+            }),
+        };
+        let api = api(cassette(vec![expired]));
+        let stats = api.stats.clone();
+        let snapshots = watch(api, Duration::from_millis(10));
+        let snapshots: Vec<_> = snapshots.take(2).collect().await;
+
+        assert!(matches!(snapshots[0], Err(Error::SessionExpired(_))));
+        /* Logged in again, although station list quota is used up by the expired call */
+        assert!(matches!(snapshots[1], Err(Error::RateExceeded(_))));
+        assert_eq!(Some(&2), stats.logins().get(stats::SUCCESS));
+    }
+
+    #[tokio::test]
+    async fn snapshots_follow_interval() {
+        let interval = Duration::from_millis(50);
+        let started_at = time::Instant::now();
+        let snapshots = watch(api(cassette(Vec::new())), interval);
+        let _: Vec<_> = snapshots.take(3).collect().await;
+
+        assert!(started_at.elapsed() >= interval * 2);
+    }
+}
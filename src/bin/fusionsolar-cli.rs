@@ -0,0 +1,100 @@
+//! Command-line client printing FusionSolar data as JSON.
+//!
+//! Credentials are read from `FS_USERNAME` and `FS_PASSWORD` environment variables, API URL
+//! optionally from `FS_API_URL`.
+use fusionsolar_rs::api;
+use fusionsolar_rs::model::LoggedInApi;
+use fusionsolar_rs::source;
+use serde::Serialize;
+use std::env;
+use std::process;
+
+const USAGE: &str = "usage: fusionsolar-cli <stations|devices|snapshot|dump-devices>";
+
+fn print<T: Serialize>(value: &T) -> Result<(), api::Error> {
+    let json = serde_json::to_string_pretty(value).or(Err(api::Error::FormatError))?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Data printed by the client.
+enum Command {
+    Stations,
+    Devices,
+    Snapshot,
+    DumpDevices,
+}
+
+impl std::str::FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stations" => Ok(Command::Stations),
+            "devices" => Ok(Command::Devices),
+            "snapshot" => Ok(Command::Snapshot),
+            "dump-devices" => Ok(Command::DumpDevices),
+            _ => Err(format!("unknown command {}", s)),
+        }
+    }
+}
+
+async fn run(command: Command, api: &LoggedInApi) -> Result<(), api::Error> {
+    match command {
+        Command::Stations => print(&api::stations(api).await?),
+        Command::Devices => {
+            let mut devices = Vec::new();
+            for station in api::stations(api).await? {
+                devices.extend(api::devices(api, &station).await?);
+            }
+            print(&devices)
+        }
+        Command::Snapshot => print(&source::snapshot(api).await?),
+        Command::DumpDevices => print(&api::dump_devices(api).await?),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    /* Validated before logging in, so that a typo does not use up login quota */
+    let command: Command = match env::args().nth(1).map(|arg| arg.parse()) {
+        Some(Ok(command)) => command,
+        Some(Err(e)) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let var = |name| {
+        env::var(name).unwrap_or_else(|_| {
+            eprintln!("{} is not set", name);
+            process::exit(2);
+        })
+    };
+    let api = api::api(
+        env::var("FS_API_URL").ok(),
+        var("FS_USERNAME"),
+        var("FS_PASSWORD"),
+    );
+
+    let result = match api::login(&api).await {
+        Ok(logged_in_api) => {
+            let result = run(command, &logged_in_api).await;
+            if let Err(e) = api::logout(logged_in_api).await {
+                log::warn!("unable to log out from API: {}", e);
+            }
+            result
+        }
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,295 @@
+//! Mock of FusionSolar Northbound API, serving fixture data.
+//!
+//! Every endpoint responds with content of `<endpoint>.json` from fixtures directory (e.g.
+//! `getStationList.json`), with `params` of the request echoed back. Device KPI are read from
+//! `getDevRealKpi_<devTypeId>.json`, falling back to `getDevRealKpi.json`, and reported for every
+//! requested device, with its `devId` in place of the one of the fixture. Sessions are
+//! established with `/login` and expire after configured time, and calls exceeding FusionSolar
+//! quotas are throttled.
+#[macro_use]
+extern crate rocket;
+
+use config::Config;
+use fusionsolar_rs::api::{endpoint, RateLimiter};
+use rocket::http::{Cookie, CookieJar};
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const XSRF_TOKEN: &str = "XSRF-TOKEN";
+
+#[derive(Clone, serde::Deserialize)]
+pub struct MockConfig {
+    /// Directory containing fixture data.
+    fixtures: String,
+    /// Credentials accepted by `/login`; any credentials are accepted if not set.
+    username: Option<String>,
+    password: Option<String>,
+    /// Seconds after which session expires, requiring login again.
+    session_ttl: u64,
+    /// Whether calls exceeding FusionSolar quotas fail with `ACCESS_FREQUENCY_IS_TOO_HIGH`.
+    throttle: bool,
+}
+
+pub fn read_settings() -> MockConfig {
+    let mut settings = Config::default();
+    settings
+        .set_default("fixtures", "resources/mock")
+        .unwrap()
+        .set_default("session_ttl", 1800)
+        .unwrap()
+        .set_default("throttle", true)
+        .unwrap()
+        .merge(config::Environment::with_prefix("FS_MOCK"))
+        .unwrap();
+
+    settings.try_into().expect("Configuration error")
+}
+
+/// State of the mock server.
+struct Mock {
+    config: MockConfig,
+    /// Login time of every active session, by XSRF token.
+    sessions: Mutex<HashMap<String, Instant>>,
+    rate_limiter: RateLimiter,
+    tokens: AtomicU64,
+}
+
+impl Mock {
+    fn new(config: MockConfig) -> Mock {
+        let mock = Mock {
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            rate_limiter: RateLimiter::new(),
+            tokens: AtomicU64::new(0),
+        };
+        mock.scale_rate_limiter();
+        mock
+    }
+
+    /// Scale quotas according to number of stations and devices in fixtures.
+    fn scale_rate_limiter(&self) {
+        let data = |endpoint| {
+            self.fixture(endpoint)
+                .and_then(|value| value.get("data").and_then(Value::as_array).cloned())
+                .unwrap_or_default()
+        };
+
+        self.rate_limiter
+            .set_stations(data(endpoint::STATIONS).len());
+
+        let mut devices: HashMap<String, usize> = HashMap::new();
+        for device in data(endpoint::DEVICES) {
+            if let Some(station_code) = device.get("stationCode").and_then(Value::as_str) {
+                *devices.entry(station_code.to_owned()).or_default() += 1;
+            }
+        }
+        for (station_code, count) in devices {
+            self.rate_limiter.set_devices(&station_code, count);
+        }
+    }
+
+    /// Read fixture of `endpoint` (e.g. `/getStationList` or `/getDevRealKpi_1`).
+    fn fixture(&self, endpoint: &str) -> Option<Value> {
+        let mut path = PathBuf::from(&self.config.fixtures);
+        path.push(format!("{}.json", endpoint.trim_start_matches('/')));
+
+        let content = std::fs::read_to_string(&path).ok()?;
+        serde_json::from_str(&content)
+            .map_err(|e| log::error!("invalid fixture {}: {}", path.display(), e))
+            .ok()
+    }
+
+    fn new_token(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let counter = self.tokens.fetch_add(1, Ordering::Relaxed);
+        format!("{:016x}{:08x}", nanos, counter)
+    }
+
+    /// Whether `token` belongs to session which has not expired yet.
+    fn session_valid(&self, token: &str) -> bool {
+        let ttl = Duration::from_secs(self.config.session_ttl);
+        match self.sessions.lock() {
+            Ok(mut sessions) => {
+                sessions.retain(|_, logged_in_at| logged_in_at.elapsed() < ttl);
+                sessions.contains_key(token)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `key` of login `body` is accepted, if it is restricted to `expected`.
+    fn accepts(expected: &Option<String>, body: &Value, key: &str) -> bool {
+        match expected {
+            Some(expected) => body.get(key).and_then(Value::as_str) == Some(expected),
+            None => true,
+        }
+    }
+}
+
+/// XSRF token sent by client in request header.
+struct XsrfToken(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for XsrfToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = request.headers().get_one(XSRF_TOKEN).map(String::from);
+        request::Outcome::Success(XsrfToken(token))
+    }
+}
+
+fn current_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn failure(fail_code: u64, data: &str) -> Json<Value> {
+    Json(json!({
+        "data": data,
+        "failCode": fail_code,
+        "params": null,
+        "success": false,
+    }))
+}
+
+#[post("/thirdData/login", data = "<body>")]
+fn login_route(body: Json<Value>, cookies: &CookieJar<'_>, mock: &State<Mock>) -> Json<Value> {
+    /* Unknown accounts are reported like by regions not serving them */
+    if !Mock::accepts(&mock.config.username, &body, "userName") {
+        return failure(20001, "USER_DOES_NOT_EXIST");
+    }
+    if !Mock::accepts(&mock.config.password, &body, "systemCode") {
+        return failure(20002, "USERNAME_OR_PASSWORD_ERROR");
+    }
+    if mock.config.throttle && mock.rate_limiter.acquire(endpoint::LOGIN).is_err() {
+        return failure(407, "ACCESS_FREQUENCY_IS_TOO_HIGH");
+    }
+
+    let token = mock.new_token();
+    if let Ok(mut sessions) = mock.sessions.lock() {
+        sessions.insert(token.clone(), Instant::now());
+    }
+    cookies.add(Cookie::new(XSRF_TOKEN, token));
+
+    Json(json!({
+        "data": null,
+        "failCode": 0,
+        "message": null,
+        "params": {"currentTime": current_time()},
+        "success": true,
+    }))
+}
+
+#[post("/thirdData/logout")]
+fn logout_route(token: XsrfToken, mock: &State<Mock>) -> Json<Value> {
+    if let (Some(token), Ok(mut sessions)) = (token.0, mock.sessions.lock()) {
+        sessions.remove(&token);
+    }
+
+    Json(json!({
+        "data": null,
+        "failCode": 0,
+        "message": null,
+        "params": {"currentTime": current_time()},
+        "success": true,
+    }))
+}
+
+/// Entries of fixture `data` repeated for every device of comma-separated `dev_ids`, reported
+/// with its ID. Fixtures hold single entry, used as a template.
+fn for_devices(data: &Value, dev_ids: &str) -> Value {
+    let template = match data.get(0) {
+        Some(template) => template,
+        None => return data.clone(),
+    };
+    let entries = dev_ids
+        .split(',')
+        .filter_map(|id| id.trim().parse::<u64>().ok())
+        .map(|id| {
+            let mut entry = template.clone();
+            entry["devId"] = json!(id);
+            entry
+        })
+        .collect();
+    Value::Array(entries)
+}
+
+#[post("/thirdData/<endpoint>", data = "<body>")]
+fn endpoint_route(
+    endpoint: &str,
+    body: Option<Json<Value>>,
+    token: XsrfToken,
+    mock: &State<Mock>,
+) -> Option<Json<Value>> {
+    let endpoint = format!("/{}", endpoint);
+    let params = body
+        .map(|body| body.into_inner())
+        .unwrap_or_else(|| json!({}));
+
+    let dev_type_id = params
+        .get("devTypeId")
+        .and_then(Value::as_str)
+        .filter(|id| id.chars().all(|c| c.is_ascii_digit()));
+    let fixture = match dev_type_id {
+        Some(dev_type_id) => mock
+            .fixture(&format!("{}_{}", endpoint, dev_type_id))
+            .or_else(|| mock.fixture(&endpoint)),
+        None => mock.fixture(&endpoint),
+    };
+    let mut response = fixture?;
+
+    if !token.0.is_some_and(|token| mock.session_valid(&token)) {
+        return Some(failure(305, "USER_MUST_RELOGIN"));
+    }
+    if mock.config.throttle && mock.rate_limiter.acquire(&endpoint).is_err() {
+        return Some(failure(407, "ACCESS_FREQUENCY_IS_TOO_HIGH"));
+    }
+
+    if let Some(dev_ids) = params.get("devIds").and_then(Value::as_str) {
+        response["data"] = for_devices(&response["data"], dev_ids);
+    }
+    let mut echoed = params;
+    if let Some(echoed) = echoed.as_object_mut() {
+        echoed.insert(String::from("currentTime"), json!(current_time()));
+    }
+    response["params"] = echoed;
+    Some(Json(response))
+}
+
+#[launch]
+fn rocket() -> _ {
+    env_logger::init();
+
+    let settings = read_settings();
+    rocket::build()
+        .manage(Mock::new(settings))
+        .mount("/", routes![login_route, logout_route, endpoint_route])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn device_kpi_are_reported_for_requested_devices() {
+        let data = json!([{"devId": 1, "dataItemMap": {"active_power": 2.0}}]);
+        let data = for_devices(&data, "1000000000000002,1000000000000003");
+
+        assert_eq!(json!(1000000000000002u64), data[0]["devId"]);
+        assert_eq!(json!(1000000000000003u64), data[1]["devId"]);
+        assert_eq!(json!(2.0), data[1]["dataItemMap"]["active_power"]);
+    }
+}
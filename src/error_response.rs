@@ -0,0 +1,43 @@
+use fusionsolar_rs::api::{self, ErrorKind};
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::io::Cursor;
+
+/// HTTP response describing library `api::Error`.
+#[derive(Debug)]
+pub struct ErrorResponse(pub api::Error);
+
+impl From<api::Error> for ErrorResponse {
+    fn from(error: api::Error) -> ErrorResponse {
+        ErrorResponse(error)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let (status, error) = match self.0.kind() {
+            ErrorKind::RateLimited => (
+                Status::TooManyRequests,
+                format!("<html><body><h3>429 Too Many Requests</h3>Downstream API response: <code>{}</code></body></html>", self.0),
+            ),
+            ErrorKind::Authentication => (
+                Status::Forbidden,
+                format!("<html><body><h3>403 Forbidden</h3>Error while authenticating to downstream API: <code>{}</code></body></html>", self.0),
+            ),
+            _ => (
+                Status::InternalServerError,
+                format!(
+                    "<html><body><h3>Unknown exception</h3><code>{}</code></body></html>",
+                    self.0
+                ),
+            ),
+        };
+
+        Response::build()
+            .status(status)
+            .sized_body(error.len(), Cursor::new(error))
+            .header(ContentType::new("text", "html"))
+            .ok()
+    }
+}
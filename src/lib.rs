@@ -1,2 +1,8 @@
+#[cfg(feature = "client")]
 pub mod api;
+#[cfg(feature = "modbus")]
+pub mod modbus;
 pub mod model;
+#[cfg(feature = "client")]
+pub mod source;
+pub mod units;
@@ -1,60 +1,282 @@
 #[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate prometheus;
-#[macro_use]
 extern crate rocket;
 
 use config::Config;
 use fusionsolar_rs::api;
-use fusionsolar_rs::model::Api;
+use fusionsolar_rs::api::{cassette, kiosk};
+use fusionsolar_rs::modbus;
+use fusionsolar_rs::model::{DeviceTypeId, Inverter, Station, TimeZones};
+use fusionsolar_rs::source::{CloudSource, DataSource, KioskSource, ModbusSource};
+use fusionsolar_rs::units::KiloWatts;
+use rocket::serde::json::Json;
 use rocket::{Build, Rocket, State};
-use std::sync::Mutex;
-use std::time::Instant;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::Duration;
 
+mod error_response;
+mod mapping;
 mod metrics;
 
-const API_URL: &str = "https://eu5.fusionsolar.huawei.com/thirdData";
+use error_response::ErrorResponse;
 
 #[derive(Clone, serde::Deserialize)]
 pub struct FusionsolarConfig {
-    api_url: String,
+    /// Overrides API URL discovered from known regions.
+    api_url: Option<String>,
+    #[serde(default)]
     username: String,
+    #[serde(default)]
     password: String,
     interval: u64,
+    /// Seconds after which series of stations and devices which are no longer reported are
+    /// removed (defaults to 3600).
+    stale_series_grace: Option<u64>,
+    /// PV strings of devices to export: `auto` (default, strings which reported non-zero values),
+    /// `all` or comma-separated string numbers.
+    pv_strings: Option<String>,
+    /// Whether metrics of the exporter process itself are exported (defaults to false).
+    #[serde(default)]
+    process_metrics: bool,
+    /// Prefix of metric names (defaults to `fusionsolar`).
+    namespace: Option<String>,
+    /// Whether metrics of previous releases are exported also under their legacy names (defaults
+    /// to true, to be changed to false in the next release).
+    legacy_metrics: Option<bool>,
+    /// Path of file mapping device KPIs to metrics, merged into the built-in mapping.
+    metric_mapping: Option<String>,
+    /// Time zone of stations, e.g. `Europe/Warsaw` (defaults to UTC).
+    time_zone: Option<String>,
+    /// Comma-separated `station_code=time_zone` pairs overriding `time_zone` per station.
+    station_time_zones: Option<String>,
+    /// Path of cassette to record API traffic to.
+    record: Option<String>,
+    /// Path of cassette to replay instead of calling the API.
+    replay: Option<String>,
+    /// Data source to collect from: `cloud`, `replay`, `kiosk` or `modbus`. Inferred from other
+    /// settings if not set.
+    source: Option<String>,
+    /// Token of kiosk share link, replacing the API (and credentials) with the kiosk.
+    kiosk_token: Option<String>,
+    /// Overrides URL of kiosk data.
+    kiosk_url: Option<String>,
+    /// Address (`host[:port]`) of inverter or SDongle to read over Modbus-TCP instead of the API.
+    modbus_addr: Option<String>,
+    /// Comma-separated Modbus unit IDs of inverters (defaults to `1`).
+    modbus_unit_ids: Option<String>,
+    /// Station code reported for inverters read over Modbus (defaults to `modbus`).
+    modbus_station_code: Option<String>,
 }
 
-/// Structure containing state for API handlers.
+impl FusionsolarConfig {
+    /// Parse configured station time zones.
+    fn time_zones(&self) -> TimeZones {
+        let parse = |name: &str| {
+            name.trim()
+                .parse()
+                .unwrap_or_else(|e| panic!("Configuration error: time zone {}: {}", name, e))
+        };
+        let mut time_zones = TimeZones::default();
+
+        if let Some(time_zone) = &self.time_zone {
+            time_zones.default = parse(time_zone);
+        }
+        for pair in self.station_time_zones.iter().flat_map(|s| s.split(',')) {
+            match pair.split_once('=') {
+                Some((code, time_zone)) => {
+                    time_zones
+                        .stations
+                        .insert(code.trim().to_owned(), parse(time_zone));
+                }
+                None => panic!("Configuration error: station time zone {}", pair),
+            }
+        }
+        time_zones
+    }
+
+    /// Resolve configured Modbus inverters.
+    fn inverters(&self) -> Vec<Inverter> {
+        let addr = match &self.modbus_addr {
+            Some(addr) if addr.contains(':') => addr.to_owned(),
+            Some(addr) => format!("{}:{}", addr, modbus::DEFAULT_PORT),
+            None => return Vec::new(),
+        };
+        let addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .unwrap_or_else(|| panic!("Configuration error: Modbus address {}", addr));
+
+        self.modbus_unit_ids
+            .as_deref()
+            .unwrap_or("1")
+            .split(',')
+            .map(|id| {
+                let unit_id = id
+                    .trim()
+                    .parse()
+                    .unwrap_or_else(|e| panic!("Configuration error: unit ID {}: {}", id, e));
+                modbus::inverter(addr, unit_id)
+            })
+            .collect()
+    }
+
+    /// Settings of metric collection.
+    fn metrics(&self) -> metrics::Config {
+        let pv_strings = self.pv_strings.as_deref().unwrap_or("auto");
+        metrics::Config {
+            stale_after: Duration::from_secs(self.stale_series_grace.unwrap_or(3600)),
+            pv_strings: pv_strings
+                .parse()
+                .unwrap_or_else(|e| panic!("Configuration error: PV strings {}", e)),
+            process_metrics: self.process_metrics,
+            namespace: match self.namespace.as_deref().map(str::trim) {
+                None => String::from("fusionsolar"),
+                Some("") => panic!("Configuration error: namespace must not be empty"),
+                Some(namespace) => namespace.to_owned(),
+            },
+            legacy_names: self.legacy_metrics.unwrap_or(true),
+            mapping: self.mapping(),
+        }
+    }
+
+    /// Load metric mapping, warning about entries the configured data source never reports
+    /// devices for.
+    fn mapping(&self) -> Vec<mapping::MappedMetric> {
+        let mapping = mapping::load(self.metric_mapping.as_deref())
+            .unwrap_or_else(|e| panic!("Configuration error: metric mapping {}", e));
+        if self.metric_mapping.is_none() {
+            return mapping;
+        }
+        match self.source_name() {
+            "kiosk" => log::warn!("metric mapping never applies, kiosk source reports no devices"),
+            "modbus" => {
+                let inverter = DeviceTypeId::StringInverter as u64;
+                for metric in mapping.iter().filter(|m| m.device_type != inverter) {
+                    log::warn!(
+                        "mapping of {} to {} never applies, modbus source reports inverters only",
+                        metric.key,
+                        metric.name
+                    );
+                }
+            }
+            _ => (),
+        }
+        mapping
+    }
+
+    /// Name of configured data source, inferred from other settings unless set explicitly.
+    fn source_name(&self) -> &str {
+        match &self.source {
+            Some(source) => source,
+            None if self.kiosk_token.is_some() => "kiosk",
+            None if self.modbus_addr.is_some() => "modbus",
+            None if self.replay.is_some() => "replay",
+            None => "cloud",
+        }
+    }
+
+    /// Create configured data source.
+    fn data_source(self) -> Arc<dyn DataSource> {
+        let time_zones = self.time_zones();
+
+        match self.source_name() {
+            "cloud" | "replay" => {
+                if self.source_name() == "replay" && self.replay.is_none() {
+                    panic!("Configuration error: replay source requires cassette to replay");
+                }
+                let cassette = self.cassette();
+                let mut api = api::api(self.api_url, self.username, self.password);
+                api.time_zones = time_zones;
+                api.cassette = cassette;
+                Arc::new(CloudSource::new(api))
+            }
+            "kiosk" => {
+                let token = self
+                    .kiosk_token
+                    .expect("Configuration error: kiosk source requires kiosk token");
+                let mut kiosk = kiosk::kiosk(self.kiosk_url, token);
+                kiosk.time_zones = time_zones;
+                Arc::new(KioskSource::new(kiosk))
+            }
+            "modbus" => {
+                let inverters = self.inverters();
+                if inverters.is_empty() {
+                    panic!("Configuration error: modbus source requires Modbus address");
+                }
+                let code = self
+                    .modbus_station_code
+                    .unwrap_or_else(|| String::from("modbus"));
+                let station = Station {
+                    capacity: KiloWatts::default(),
+                    name: code.to_owned(),
+                    time_zone: time_zones.of(&code),
+                    code,
+                };
+                Arc::new(ModbusSource::new(station, inverters))
+            }
+            source => panic!("Configuration error: unknown source {}", source),
+        }
+    }
+
+    /// Open configured cassette.
+    fn cassette(&self) -> cassette::Mode {
+        match (&self.record, &self.replay) {
+            (Some(_), Some(_)) => panic!("Configuration error: both record and replay configured"),
+            (Some(path), None) => cassette::Mode::Record(Arc::new(
+                cassette::Recorder::create(path)
+                    .unwrap_or_else(|e| panic!("Configuration error: cassette {}: {}", path, e)),
+            )),
+            (None, Some(path)) => cassette::Mode::Replay(Arc::new(
+                cassette::Player::open(path)
+                    .unwrap_or_else(|e| panic!("Configuration error: cassette {}: {}", path, e)),
+            )),
+            (None, None) => cassette::Mode::Live,
+        }
+    }
+}
+
+/// Structure containing state for API handlers, shared with background collection.
 pub struct StateData {
-    api: Api,
-    interval: u64,
-    /// Timestamp of last successful metric collection via `metrics::collect()`
-    timestamp: Mutex<Option<Instant>>,
+    source: Arc<dyn DataSource>,
+    metrics: metrics::Metrics,
+    /// Held while metrics are collected, so that concurrent refreshes do not collect twice.
+    collecting: tokio::sync::Mutex<()>,
 }
 
 impl StateData {
-    /// Updates `timestamp` to `now()`.
-    fn touch(&self) {
-        if let Ok(mut ts) = self.timestamp.lock() {
-            *ts = Some(Instant::now());
-        } else {
-            log::trace!("Unable to lock timestamp mutex, will refresh again")
+    /// Collect metrics from the data source. If collection is already in progress, waits for it
+    /// to finish instead of starting another one.
+    async fn refresh(&self) {
+        let _collecting = match self.collecting.try_lock() {
+            Ok(collecting) => collecting,
+            Err(_) => {
+                log::debug!("collection already in progress, waiting for it to finish");
+                drop(self.collecting.lock().await);
+                return;
+            }
+        };
+        /* Failure is reported by `fusionsolar_up`, together with data of last collection */
+        match self.metrics.collect(self.source.as_ref()).await {
+            Ok(collection) => {
+                log::info!("{} from {}", collection, self.source.name());
+                for error in &collection.errors {
+                    log::warn!("unable to collect {}", error);
+                }
+            }
+            Err(e) => log::error!("collection from {} failed: {}", self.source.name(), e),
         }
     }
 
-    /// Checks whether `interval_seconds` elapsed since last `touch()`
-    fn interval_elapsed(&self, interval_secs: u64) -> bool {
-        let elapsed_opt = self
-            .timestamp
-            .lock()
-            .ok()
-            .and_then(|a| a.map(|b| b.elapsed().as_secs()));
-
-        if let Some(elapsed) = elapsed_opt {
-            elapsed > interval_secs
-        } else {
-            /* If there is None timestamp/elapsed, always return true to trigger action */
-            true
+    /// Refresh metrics every `interval`, starting immediately.
+    async fn collect_every(&self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.refresh().await;
         }
     }
 }
@@ -63,47 +285,76 @@ pub fn read_settings() -> FusionsolarConfig {
     let mut settings = Config::default();
     settings
         .merge(config::Environment::with_prefix("FS"))
-        .unwrap()
-        .set_default("api_url", API_URL)
-        .unwrap()
-        .set_default("api_url", API_URL)
         .unwrap();
 
     settings.try_into().expect("Configuration error")
 }
 
 #[get("/metrics")]
-async fn metrics_route(state: &State<StateData>) -> Result<String, api::Error> {
-    if state.interval_elapsed(state.interval) {
-        metrics::collect(&state.api).await?;
-        state.touch();
-    } else {
-        log::info!("interval time not yet elapsed since last run; returning cached result")
-    }
-    metrics::read().await
+async fn metrics_route(state: &State<Arc<StateData>>) -> Result<String, ErrorResponse> {
+    Ok(state.metrics.read()?)
+}
+
+/// Collect metrics on demand, without waiting for next interval, and return them.
+#[post("/refresh")]
+async fn refresh_route(state: &State<Arc<StateData>>) -> Result<String, ErrorResponse> {
+    state.refresh().await;
+    Ok(state.metrics.read()?)
 }
 
 #[get("/dump-devices")]
-async fn dump_devices_route(state: &State<StateData>) -> Result<String, api::Error> {
-    let logged_in_api = api::login(&state.api).await?;
-    let dump = api::dump_devices(&logged_in_api).await?;
+async fn dump_devices_route(
+    state: &State<Arc<StateData>>,
+) -> Result<Json<HashMap<u64, Value>>, ErrorResponse> {
+    let dump = state.source.dump_devices().await?;
+
+    Ok(Json(dump))
+}
 
-    Ok(format!("{:#?}", dump))
+fn rocket(state: Arc<StateData>) -> Rocket<Build> {
+    rocket::build().manage(state).mount(
+        "/",
+        routes![metrics_route, refresh_route, dump_devices_route],
+    )
 }
 
-#[launch]
-fn rocket() -> Rocket<Build> {
+#[rocket::main]
+async fn main() {
     env_logger::init();
 
     let settings = read_settings();
-    let api = api::api(settings.api_url, settings.username, settings.password);
-    let state = StateData {
-        api,
-        interval: settings.interval,
-        timestamp: Mutex::new(None),
+    let interval = Duration::from_secs(settings.interval.max(1));
+    if settings.source_name() == "cloud" && interval < api::rate_limit::min_interval() {
+        log::warn!(
+            "interval of {}s is shorter than API quotas allow ({}s), some collections will fail",
+            interval.as_secs(),
+            api::rate_limit::min_interval().as_secs()
+        );
+    }
+    let metrics = metrics::Metrics::new(settings.metrics())
+        .unwrap_or_else(|e| panic!("unable to create metrics registry: {}", e));
+    let source = settings.data_source();
+    log::info!("collecting from {} data source", source.name());
+
+    let state = Arc::new(StateData {
+        source: source.clone(),
+        metrics,
+        collecting: tokio::sync::Mutex::new(()),
+    });
+    let collection = {
+        let state = state.clone();
+        tokio::spawn(async move { state.collect_every(interval).await })
     };
 
-    rocket::build()
-        .manage(state)
-        .mount("/", routes![metrics_route, dump_devices_route])
+    if let Err(e) = rocket(state.clone()).launch().await {
+        log::error!("server failed: {}", e);
+    }
+    /* Let in-flight collection finish before closing the source */
+    let collecting = state.collecting.lock().await;
+    collection.abort();
+    drop(collecting);
+    match source.close().await {
+        Ok(()) => log::info!("closed {} data source", source.name()),
+        Err(e) => log::warn!("unable to close {} data source: {}", source.name(), e),
+    }
 }
@@ -0,0 +1,291 @@
+use crate::metrics;
+use config::{Config, ConfigError, File, FileFormat, Source};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Built-in mapping, which entries of configured mapping file are merged into.
+const DEFAULT_MAPPING: &str = include_str!("../resources/metric-mapping.toml");
+/// Device types of the API (e.g. `1` for string inverter, `62` for dongle), which devices are
+/// reported as.
+const DEVICE_TYPES: &[u64] = &[
+    1, 2, 8, 10, 13, 16, 17, 22, 37, 38, 39, 40, 41, 45, 46, 47, 62, 63, 70,
+];
+
+/// How mapped KPI is exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    #[default]
+    Gauge,
+    /// Lifetime total, ignoring occasional rollbacks.
+    Counter,
+}
+
+/// Metric exported from KPI `key` of devices of `device_type`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappedMetric {
+    pub device_type: u64,
+    pub key: String,
+    /// Name of the metric, without namespace. Required unless disabled.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub help: String,
+    #[serde(default, rename = "type")]
+    pub metric_type: MetricType,
+    /// Factor the KPI value is multiplied with.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Labels added to the ones of device.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Whether the KPI is exported, so that file can remove built-in entry by replacing it.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Mapping {
+    #[serde(default)]
+    metric: Vec<MappedMetric>,
+}
+
+fn parse(source: impl Source + Send + Sync + 'static) -> Result<Vec<MappedMetric>, ConfigError> {
+    let mut config = Config::default();
+    config.merge(source)?;
+    Ok(config.try_into::<Mapping>()?.metric)
+}
+
+/// Load built-in mapping, merged with mapping file at `path` (TOML, JSON or YAML), if any.
+pub fn load(path: Option<&str>) -> Result<Vec<MappedMetric>, ConfigError> {
+    let defaults = parse(File::from_str(DEFAULT_MAPPING, FileFormat::Toml))?;
+    let file = match path {
+        Some(path) => parse(File::with_name(path))?,
+        None => Vec::new(),
+    };
+    merge(defaults, file)
+}
+
+/// Merge entries of mapping `file` into `mapping`, replacing those with the same device type and
+/// key, and validate the result. Disabled entries are left out.
+fn merge(
+    mut mapping: Vec<MappedMetric>,
+    file: Vec<MappedMetric>,
+) -> Result<Vec<MappedMetric>, ConfigError> {
+    for metric in file {
+        mapping.retain(|m| (m.device_type, &m.key) != (metric.device_type, &metric.key));
+        mapping.push(metric);
+    }
+    mapping.retain(|metric| metric.enabled);
+
+    let mut by_name: HashMap<&str, &MappedMetric> = HashMap::new();
+    for metric in &mapping {
+        validate(metric)?;
+        match by_name.get(metric.name.as_str()) {
+            Some(first)
+                if first.help != metric.help
+                    || first.metric_type != metric.metric_type
+                    || !first.labels.keys().eq(metric.labels.keys()) =>
+            {
+                return Err(invalid(
+                    metric,
+                    format!(
+                        "differs in help, type or label names from mapping of {}",
+                        first.key
+                    ),
+                ));
+            }
+            Some(_) => (),
+            None => {
+                by_name.insert(&metric.name, metric);
+            }
+        }
+        if !DEVICE_TYPES.contains(&metric.device_type) {
+            log::warn!(
+                "mapping of {} to {} never applies, no device is reported as type {}",
+                metric.key,
+                metric.name,
+                metric.device_type
+            );
+        }
+    }
+    Ok(mapping)
+}
+
+fn invalid(metric: &MappedMetric, reason: String) -> ConfigError {
+    ConfigError::Message(format!(
+        "entry of {} of device type {} {}",
+        metric.key, metric.device_type, reason
+    ))
+}
+
+/// Check that name and labels of `metric` are valid and do not clash with built-in ones.
+fn validate(metric: &MappedMetric) -> Result<(), ConfigError> {
+    if metric.name.is_empty() {
+        return Err(invalid(metric, String::from("has no name")));
+    }
+    if !valid_name(&metric.name, true) {
+        return Err(invalid(metric, format!("has invalid name {}", metric.name)));
+    }
+    if metrics::names().any(|name| name == metric.name) {
+        return Err(invalid(
+            metric,
+            format!("has name {} of built-in metric", metric.name),
+        ));
+    }
+    for label in metric.labels.keys() {
+        if !valid_name(label, false) || label.starts_with("__") {
+            return Err(invalid(metric, format!("has invalid label {}", label)));
+        }
+        if metrics::DEVICE.contains(&label.as_str()) {
+            return Err(invalid(
+                metric,
+                format!("has label {} set to every device", label),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` is valid name of metric (with `colons`) or of label.
+fn valid_name(name: &str, colons: bool) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || (colons && c == ':');
+    !name.starts_with(|c: char| c.is_ascii_digit()) && !name.is_empty() && name.chars().all(valid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn toml(content: &str) -> Vec<MappedMetric> {
+        parse(File::from_str(content, FileFormat::Toml)).unwrap()
+    }
+
+    fn defaults() -> Vec<MappedMetric> {
+        toml(DEFAULT_MAPPING)
+    }
+
+    fn entry(key: &str, name: &str) -> String {
+        format!(
+            "[[metric]]\ndevice_type = 1\nkey = \"{}\"\nname = \"{}\"\nhelp = \"help\"\n",
+            key, name
+        )
+    }
+
+    #[test]
+    fn default_mapping_is_valid() {
+        let mapping = load(None).unwrap();
+        let mppt_power = mapping.iter().find(|m| m.key == "mppt_power").unwrap();
+        assert_eq!("device_mppt_power_watts", mppt_power.name);
+        assert_eq!(1000.0, mppt_power.scale);
+        assert_eq!(MetricType::Gauge, mppt_power.metric_type);
+        let pv_voltage = mapping.iter().find(|m| m.key == "pv24_u").unwrap();
+        assert_eq!("device_pv_voltage_volts", pv_voltage.name);
+        assert_eq!(
+            Some("pv24"),
+            pv_voltage.labels.get("string").map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn type_and_scale_are_parsed() {
+        let mapping = toml(&format!(
+            "{}type = \"counter\"\nscale = 0.001\nlabels = {{ phase = \"L1\" }}\n{}",
+            entry("total_cap", "device_total_energy_mwh_total"),
+            entry("a_u", "device_grid_voltage_volts")
+        ));
+        assert_eq!(MetricType::Counter, mapping[0].metric_type);
+        assert_eq!(0.001, mapping[0].scale);
+        assert_eq!(
+            Some("L1"),
+            mapping[0].labels.get("phase").map(String::as_str)
+        );
+        assert_eq!(MetricType::Gauge, mapping[1].metric_type);
+        assert_eq!(1.0, mapping[1].scale);
+        assert!(mapping[1].enabled);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let content = format!(
+            "{}sclae = 1000\n",
+            entry("a_u", "device_grid_voltage_volts")
+        );
+        assert!(parse(File::from_str(&content, FileFormat::Toml)).is_err());
+    }
+
+    #[test]
+    fn file_replaces_and_disables_defaults() {
+        let file = toml(&format!(
+            "{}{}[[metric]]\ndevice_type = 1\nkey = \"inverter_state\"\nenabled = false\n",
+            entry("efficiency", "device_efficiency_ratio"),
+            entry("mppt_1_cap", "device_mppt1_energy_kwh")
+        ));
+        let mapping = merge(defaults(), file).unwrap();
+
+        let efficiency: Vec<&str> = mapping
+            .iter()
+            .filter(|m| m.key == "efficiency")
+            .map(|m| m.name.as_str())
+            .collect();
+        assert_eq!(vec!["device_efficiency_ratio"], efficiency);
+        assert!(mapping.iter().any(|m| m.key == "mppt_1_cap"));
+        assert!(!mapping.iter().any(|m| m.key == "inverter_state"));
+        assert_eq!(defaults().len(), mapping.len());
+    }
+
+    #[test]
+    fn entries_without_name_are_rejected() {
+        let file = toml("[[metric]]\ndevice_type = 1\nkey = \"a_u\"\n");
+        assert!(merge(defaults(), file).is_err());
+    }
+
+    #[test]
+    fn collisions_with_built_in_metrics_are_rejected() {
+        let file = toml(&entry("temperature", "device_pv_power_watts"));
+        let error = merge(defaults(), file).unwrap_err().to_string();
+        assert!(error.contains("built-in metric"), "{}", error);
+
+        let file = toml(&format!(
+            "{}labels = {{ device_id = \"1\" }}\n",
+            entry("a_u", "device_grid_voltage_volts")
+        ));
+        let error = merge(defaults(), file).unwrap_err().to_string();
+        assert!(error.contains("label device_id"), "{}", error);
+    }
+
+    #[test]
+    fn entries_sharing_name_must_match() {
+        let shared = |phase: &str, label: &str| {
+            format!(
+                "{}labels = {{ {} = \"{}\" }}\n",
+                entry(&format!("{}_u", phase), "device_grid_voltage_volts"),
+                label,
+                phase
+            )
+        };
+        let file = toml(&format!("{}{}", shared("a", "phase"), shared("b", "phase")));
+        assert!(merge(defaults(), file).is_ok());
+
+        let file = toml(&format!("{}{}", shared("a", "phase"), shared("b", "line")));
+        assert!(merge(defaults(), file).is_err());
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        assert!(valid_name("device_grid:voltage", true));
+        assert!(!valid_name("device_grid:voltage", false));
+        assert!(!valid_name("1_device", true));
+        assert!(!valid_name("device-grid", true));
+        assert!(!valid_name("", true));
+    }
+}
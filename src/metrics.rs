@@ -1,120 +1,1769 @@
+use crate::mapping::{MappedMetric, MetricType};
+use chrono::Utc;
 use fusionsolar_rs::api;
-use fusionsolar_rs::model::{Api, DeviceRealKpi, DeviceTypeId, LoggedInApi, Station};
-use prometheus::{Encoder, GaugeVec, TextEncoder};
-
-lazy_static! {
-    static ref DAY_POWER_GAUGE: GaugeVec = register_gauge_vec!(
-        opts!(
-            "day_power",
-            "total amount of power generated in current day (in kWh)",
-        ),
-        &["station_code"],
-    )
-    .unwrap();
-    static ref DEVICE_ACTIVE_POWER_GAUGE: GaugeVec = register_gauge_vec!(
-        opts!(
-            "device_active_power",
-            "active power production reported by inverter",
-        ),
-        &["station_code", "device_id", "device_type_id",],
-    )
-    .unwrap();
-    static ref DEVICE_TEMPERAURE_GAUGE: GaugeVec = register_gauge_vec!(
-        opts!("device_temperature", "device reported temperature",),
-        &["station_code", "device_id", "device_type_id",],
-    )
-    .unwrap();
-}
-
-/// Process DeviceRealKpi `device_real_kpi` of `device` installed in `station` and feed them to
-/// Prometheus metrics. Based on device type, different KPIs can be presented.
-fn process_device_real_kpi(
-    dev_real_kpi: &DeviceRealKpi,
-    station: &Station,
-    device: fusionsolar_rs::model::Device,
-) {
-    if (num::FromPrimitive::from_u64(device.type_id) as Option<DeviceTypeId>).is_some() {
-        if let Some(active_power) = dev_real_kpi.active_power {
-            DEVICE_ACTIVE_POWER_GAUGE
-                .with_label_values(&[
-                    &station.code,
-                    &dev_real_kpi.id.to_string(),
-                    &(device.type_id).to_string(),
-                ])
-                .set(active_power);
-        }
-
-        if let Some(temperature) = dev_real_kpi.temperature {
-            DEVICE_TEMPERAURE_GAUGE
-                .with_label_values(&[
-                    &station.code,
-                    &dev_real_kpi.id.to_string(),
-                    &(device.type_id).to_string(),
-                ])
-                .set(temperature);
-        }
-    }
-}
-
-/// Iterate through all devices within station and collect KPI for supported ones.
-async fn collect_station_devices(api: &LoggedInApi, station: &Station) -> Result<(), api::Error> {
-    let devices = api::devices(api, station).await?;
-
-    for device in devices {
-        if let Ok(dev_kpi_vec) = api::device_real_kpi(api, &device).await {
-            if let Some(dev_real_kpi) = dev_kpi_vec.get(0) {
-                process_device_real_kpi(dev_real_kpi, station, device);
-            } else {
-                log::error!(
-                    "No KPI returned for device {} of station {}",
-                    device.id,
-                    station.code
+use fusionsolar_rs::api::Stats;
+use fusionsolar_rs::api::{endpoint, RateLimiter};
+use fusionsolar_rs::model::{Device, DeviceRealKpi, DeviceTypeId, Station, StationRealKpi};
+use fusionsolar_rs::source::DataSource;
+use fusionsolar_rs::units::{KiloWattHours, Watts};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{CounterVec, Encoder, GaugeVec, Opts, Registry, TextEncoder};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Labels of station series.
+const STATION: &[&str] = &["station_code"];
+/// Labels of device series.
+pub const DEVICE: &[&str] = &["station_code", "device_id", "device_type_id"];
+/// Labels of device series per phase, line, PV string or MPPT.
+const DEVICE_AC_PHASE: &[&str] = &["station_code", "device_id", "device_type_id", "phase"];
+const DEVICE_PV_STRING: &[&str] = &["station_code", "device_id", "device_type_id", "string"];
+const DEVICE_MPPT: &[&str] = &["station_code", "device_id", "device_type_id", "mppt"];
+/// Labels of collection errors of stations and devices, by kind only, as messages (logged
+/// instead) would create a new series for almost every error.
+const STATION_ERROR: &[&str] = &["station_code", "kind"];
+const DEVICE_ERROR: &[&str] = &["station_code", "device_id", "device_type_id", "kind"];
+
+/// How stored value of metric is rendered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Gauge,
+    Counter,
+    /// Gauge of seconds elapsed since stored timestamp (in milliseconds since epoch).
+    Age,
+}
+
+/// Name of metric in releases before introduction of namespace and unit suffixes, exported in
+/// legacy mode.
+#[derive(Debug, Clone)]
+struct Legacy {
+    name: &'static str,
+    help: &'static str,
+    /// Factor converting value of the metric to unit of the legacy one.
+    scale: f64,
+}
+
+/// Legacy names of metrics of previous releases, which are defined by default metric mapping now.
+static MAPPED_LEGACY: &[(&str, Legacy)] = &[
+    (
+        "device_active_power_watts",
+        Legacy {
+            name: "device_active_power",
+            help: "active power production reported by inverter",
+            scale: 0.001,
+        },
+    ),
+    (
+        "device_temperature_celsius",
+        Legacy {
+            name: "device_temperature",
+            help: "device reported temperature",
+            scale: 1.0,
+        },
+    ),
+];
+
+/// Definition of exported metric.
+#[derive(Debug, Clone)]
+struct Metric {
+    /// Name of the metric, without namespace.
+    name: Cow<'static, str>,
+    help: Cow<'static, str>,
+    kind: Kind,
+    labels: &'static [&'static str],
+    /// Labels following `labels`, added by metric mapping.
+    mapped_labels: Vec<String>,
+    /// Whether series of the metric belong to stations and devices, which are removed once not
+    /// updated for configured grace period.
+    expires: bool,
+    legacy: Option<Legacy>,
+}
+
+impl Metric {
+    const fn station(name: &'static str, help: &'static str, kind: Kind) -> Metric {
+        Metric::device(name, help, kind, STATION)
+    }
+
+    const fn device(
+        name: &'static str,
+        help: &'static str,
+        kind: Kind,
+        labels: &'static [&'static str],
+    ) -> Metric {
+        Metric {
+            name: Cow::Borrowed(name),
+            help: Cow::Borrowed(help),
+            kind,
+            labels,
+            mapped_labels: Vec::new(),
+            expires: true,
+            legacy: None,
+        }
+    }
+
+    /// Metric of device KPI defined by entry of metric mapping.
+    fn mapped(mapped: &MappedMetric) -> Metric {
+        Metric {
+            name: Cow::Owned(mapped.name.to_owned()),
+            help: Cow::Owned(mapped.help.to_owned()),
+            kind: match mapped.metric_type {
+                MetricType::Gauge => Kind::Gauge,
+                MetricType::Counter => Kind::Counter,
+            },
+            labels: DEVICE,
+            mapped_labels: mapped.labels.keys().cloned().collect(),
+            expires: true,
+            legacy: MAPPED_LEGACY
+                .iter()
+                .find(|(name, _)| *name == mapped.name)
+                .map(|(_, legacy)| legacy.clone()),
+        }
+    }
+
+    const fn health(
+        name: &'static str,
+        help: &'static str,
+        kind: Kind,
+        labels: &'static [&'static str],
+    ) -> Metric {
+        Metric {
+            name: Cow::Borrowed(name),
+            help: Cow::Borrowed(help),
+            kind,
+            labels,
+            mapped_labels: Vec::new(),
+            expires: false,
+            legacy: None,
+        }
+    }
+
+    const fn legacy(self, name: &'static str, help: &'static str) -> Metric {
+        self.legacy_scaled(name, help, 1.0)
+    }
+
+    const fn legacy_scaled(mut self, name: &'static str, help: &'static str, scale: f64) -> Metric {
+        self.legacy = Some(Legacy { name, help, scale });
+        self
+    }
+
+    /// Names of all labels of the metric.
+    fn label_names(&self) -> Vec<&str> {
+        self.labels
+            .iter()
+            .copied()
+            .chain(self.mapped_labels.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+static DAY_ENERGY: Metric = Metric::station(
+    "station_day_energy_kwh",
+    "energy generated in current day (in kWh)",
+    Kind::Gauge,
+)
+.legacy(
+    "day_power",
+    "total amount of power generated in current day (in kWh)",
+);
+static MONTH_ENERGY: Metric = Metric::station(
+    "station_month_energy_kwh",
+    "energy generated in current month (in kWh)",
+    Kind::Gauge,
+);
+static YEAR_ENERGY: Metric = Metric::station(
+    "station_year_energy_kwh",
+    "energy generated in current year (in kWh)",
+    Kind::Gauge,
+);
+static STATION_ENERGY: Metric = Metric::station(
+    "station_energy_kwh_total",
+    "energy generated since station was connected (in kWh)",
+    Kind::Counter,
+);
+static STATION_POWER: Metric = Metric::station(
+    "station_power_watts",
+    "current output power of station (in W)",
+    Kind::Gauge,
+);
+static CO2_REDUCTION: Metric = Metric::station(
+    "station_co2_reduction_tonnes",
+    "CO2 emissions avoided since station was connected (in t)",
+    Kind::Gauge,
+);
+static DEVICE_PV_POWER: Metric = Metric::device(
+    "device_pv_power_watts",
+    "input power of PV string, derived from its voltage and current (in W)",
+    Kind::Gauge,
+    DEVICE_PV_STRING,
+);
+static DEVICE_MPPT_ENERGY: Metric = Metric::device(
+    "device_mppt_energy_kwh_total",
+    "energy generated by MPPT over lifetime of device (in kWh)",
+    Kind::Counter,
+    DEVICE_MPPT,
+);
+static DEVICE_DATA_AGE: Metric = Metric::device(
+    "device_data_age_seconds",
+    "age of last sample collected from device (in seconds)",
+    Kind::Age,
+    DEVICE,
+);
+static DEVICE_LAST_SUCCESS: Metric = Metric::device(
+    "device_last_success_timestamp_seconds",
+    "time of last successful collection of device KPI (in seconds since epoch)",
+    Kind::Gauge,
+    DEVICE,
+);
+static DEVICE_BATTERY_CHARGE: Metric = Metric::device(
+    "device_battery_state_of_charge_percent",
+    "state of charge of battery connected to inverter (in %)",
+    Kind::Gauge,
+    DEVICE,
+);
+static DEVICE_BATTERY_POWER: Metric = Metric::device(
+    "device_battery_power_watts",
+    "charging power of battery, negative while discharging (in W)",
+    Kind::Gauge,
+    DEVICE,
+);
+static DEVICE_BATTERY_CHARGED: Metric = Metric::device(
+    "device_battery_charged_energy_kwh_total",
+    "energy charged into battery over its lifetime (in kWh)",
+    Kind::Counter,
+    DEVICE,
+);
+static DEVICE_BATTERY_DISCHARGED: Metric = Metric::device(
+    "device_battery_discharged_energy_kwh_total",
+    "energy discharged from battery over its lifetime (in kWh)",
+    Kind::Counter,
+    DEVICE,
+);
+static DEVICE_METER_POWER: Metric = Metric::device(
+    "device_meter_active_power_watts",
+    "power fed into the grid at power meter, negative while drawing from it (in W)",
+    Kind::Gauge,
+    DEVICE,
+);
+static DEVICE_METER_VOLTAGE: Metric = Metric::device(
+    "device_meter_voltage_volts",
+    "grid voltage of phase at power meter (in V)",
+    Kind::Gauge,
+    DEVICE_AC_PHASE,
+);
+static DEVICE_METER_CURRENT: Metric = Metric::device(
+    "device_meter_current_amperes",
+    "grid current of phase at power meter (in A)",
+    Kind::Gauge,
+    DEVICE_AC_PHASE,
+);
+static DEVICE_METER_EXPORTED: Metric = Metric::device(
+    "device_meter_exported_energy_kwh_total",
+    "energy fed into the grid, measured by power meter (in kWh)",
+    Kind::Counter,
+    DEVICE,
+);
+static DEVICE_METER_IMPORTED: Metric = Metric::device(
+    "device_meter_imported_energy_kwh_total",
+    "energy drawn from the grid, measured by power meter (in kWh)",
+    Kind::Counter,
+    DEVICE,
+);
+static STATION_ERRORS: Metric = Metric::device(
+    "station_errors_total",
+    "number of failed collections of station KPI or devices by kind of error",
+    Kind::Counter,
+    STATION_ERROR,
+);
+static STATION_LAST_ERROR_TIME: Metric = Metric::device(
+    "station_last_error_timestamp_seconds",
+    "time of last error of station, unless collected successfully since",
+    Kind::Gauge,
+    STATION_ERROR,
+);
+static DEVICE_ERRORS: Metric = Metric::device(
+    "device_errors_total",
+    "number of failed collections of device KPI by kind of error",
+    Kind::Counter,
+    DEVICE_ERROR,
+);
+static DEVICE_LAST_ERROR_TIME: Metric = Metric::device(
+    "device_last_error_timestamp_seconds",
+    "time of last error of device, unless collected successfully since",
+    Kind::Gauge,
+    DEVICE_ERROR,
+);
+static COLLECTED_STATIONS: Metric = Metric::health(
+    "collected_stations",
+    "number of stations in last collection by result",
+    Kind::Gauge,
+    &["result"],
+);
+static COLLECTED_DEVICES: Metric = Metric::health(
+    "collected_devices",
+    "number of devices in last collection by result",
+    Kind::Gauge,
+    &["result"],
+);
+static API_RATE_LIMIT_REMAINING: Metric = Metric::health(
+    "api_rate_limit_remaining",
+    "number of calls to API endpoint remaining within client-side budget",
+    Kind::Gauge,
+    &["endpoint"],
+);
+static UP: Metric = Metric::health(
+    "up",
+    "whether last collection from data source succeeded",
+    Kind::Gauge,
+    &[],
+);
+static LAST_SUCCESS: Metric = Metric::health(
+    "last_success_timestamp_seconds",
+    "time of last successful collection (in seconds since epoch)",
+    Kind::Gauge,
+    &[],
+);
+static COLLECTION_DURATION: Metric = Metric::health(
+    "collection_duration_seconds",
+    "duration of last collection (in seconds)",
+    Kind::Gauge,
+    &[],
+);
+static API_REQUESTS: Metric = Metric::health(
+    "api_requests_total",
+    "number of calls to API endpoint by outcome",
+    Kind::Counter,
+    &["endpoint", "outcome"],
+);
+static RATE_LIMITED: Metric = Metric::health(
+    "rate_limited_total",
+    "number of calls rejected because of exceeded quota",
+    Kind::Counter,
+    &[],
+);
+static LOGINS: Metric = Metric::health(
+    "login_total",
+    "number of logins to API by result",
+    Kind::Counter,
+    &["result"],
+);
+
+/// All exported metrics, in order of rendering.
+static METRICS: &[&Metric] = &[
+    &DAY_ENERGY,
+    &MONTH_ENERGY,
+    &YEAR_ENERGY,
+    &STATION_ENERGY,
+    &STATION_POWER,
+    &CO2_REDUCTION,
+    &DEVICE_PV_POWER,
+    &DEVICE_MPPT_ENERGY,
+    &DEVICE_DATA_AGE,
+    &DEVICE_LAST_SUCCESS,
+    &DEVICE_BATTERY_CHARGE,
+    &DEVICE_BATTERY_POWER,
+    &DEVICE_BATTERY_CHARGED,
+    &DEVICE_BATTERY_DISCHARGED,
+    &DEVICE_METER_POWER,
+    &DEVICE_METER_VOLTAGE,
+    &DEVICE_METER_CURRENT,
+    &DEVICE_METER_EXPORTED,
+    &DEVICE_METER_IMPORTED,
+    &STATION_ERRORS,
+    &STATION_LAST_ERROR_TIME,
+    &DEVICE_ERRORS,
+    &DEVICE_LAST_ERROR_TIME,
+    &COLLECTED_STATIONS,
+    &COLLECTED_DEVICES,
+    &API_RATE_LIMIT_REMAINING,
+    &UP,
+    &LAST_SUCCESS,
+    &COLLECTION_DURATION,
+    &API_REQUESTS,
+    &RATE_LIMITED,
+    &LOGINS,
+];
+
+/// Names of all built-in metrics, without namespace.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    METRICS.iter().map(|metric| metric.name.as_ref())
+}
+
+/// Which PV strings of devices are exported. MPPTs are exported once they report non-zero
+/// energy, or all of them with `All`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PvStrings {
+    /// Only those which reported non-zero values at least once, skipping unconnected inputs.
+    Auto,
+    All,
+    /// Only those with listed numbers.
+    Only(Vec<u8>),
+}
+
+impl std::str::FromStr for PvStrings {
+    type Err = String;
+
+    /// Parse `auto`, `all` or comma-separated string numbers (e.g. `1,2`).
+    fn from_str(s: &str) -> Result<PvStrings, String> {
+        match s.trim() {
+            "auto" => Ok(PvStrings::Auto),
+            "all" => Ok(PvStrings::All),
+            numbers => numbers
+                .split(',')
+                .map(|n| n.trim().parse().map_err(|e| format!("{}: {}", n, e)))
+                .collect::<Result<_, _>>()
+                .map(PvStrings::Only),
+        }
+    }
+}
+
+/// Settings of metric collection.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Series of stations and devices not updated within this period are removed.
+    pub stale_after: Duration,
+    pub pv_strings: PvStrings,
+    /// Whether metrics of the exporter process itself (`process_*`) are exported.
+    pub process_metrics: bool,
+    /// Prefix of metric names (e.g. `fusionsolar`), never empty, so that `up` does not clash with
+    /// the one of Prometheus.
+    pub namespace: String,
+    /// Whether metrics of previous releases are exported also under their names from before
+    /// introduction of namespace and unit suffixes (e.g. `day_power`).
+    pub legacy_names: bool,
+    /// Metrics exported from device KPIs, by default mapping merged with the configured one.
+    pub mapping: Vec<MappedMetric>,
+}
+
+/// Error of collection from single station or device.
+#[derive(Debug)]
+pub struct CollectionError {
+    pub station_code: String,
+    /// Device the error relates to, unless it relates to the station itself.
+    pub device_id: Option<u64>,
+    pub error: api::Error,
+}
+
+impl fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.device_id {
+            Some(device_id) => write!(
+                f,
+                "device {} of station {}: {}",
+                device_id, self.station_code, self.error
+            ),
+            None => write!(f, "station {}: {}", self.station_code, self.error),
+        }
+    }
+}
+
+/// Result of single collection, which continues past stations and devices failing to collect.
+/// Station is failed if its KPI or devices could not be read, or none of its devices could be
+/// collected.
+#[derive(Debug, Default)]
+pub struct Collection {
+    pub collected_stations: usize,
+    pub failed_stations: usize,
+    pub collected_devices: usize,
+    pub failed_devices: usize,
+    pub errors: Vec<CollectionError>,
+}
+
+impl Collection {
+    /// Whether any station was collected, or there was nothing to collect. Otherwise the data
+    /// source is considered down.
+    pub fn is_up(&self) -> bool {
+        self.collected_stations > 0 || self.failed_stations == 0
+    }
+}
+
+impl fmt::Display for Collection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "collected {} of {} stations and {} of {} devices",
+            self.collected_stations,
+            self.collected_stations + self.failed_stations,
+            self.collected_devices,
+            self.collected_devices + self.failed_devices
+        )
+    }
+}
+
+/// Latest value of single series.
+struct Sample {
+    kind: Kind,
+    /// Whether the series is removed once not updated (see `Metric::expires`).
+    expires: bool,
+    value: f64,
+    updated_at: Instant,
+}
+
+/// Period for which last values of counters removed as stale are kept, so that counters of devices
+/// returning e.g. after repair do not appear reset, while those of devices gone for good do not
+/// accumulate.
+const EXPIRED_COUNTERS_KEPT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Latest values of all series, stored by collection and rendered at gather time.
+#[derive(Default)]
+struct Store {
+    /// Series by metric name and label values.
+    series: HashMap<(Cow<'static, str>, Vec<String>), Sample>,
+    /// PV strings and MPPTs (by device and number) which reported non-zero values at least once.
+    used_pv_inputs: HashSet<(String, String)>,
+    /// Last values of counter series removed as stale, which the counters continue from if the
+    /// series are reported again, so that they do not appear reset, with time of the removal.
+    expired_counters: HashMap<(Cow<'static, str>, Vec<String>), (f64, Instant)>,
+}
+
+impl Store {
+    /// Set series `labels` of `metric` to `value`.
+    fn set(&mut self, metric: &Metric, labels: &[&str], value: f64) {
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        self.series.insert(
+            (metric.name.clone(), labels),
+            Sample {
+                kind: metric.kind,
+                expires: metric.expires,
+                value,
+                updated_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Last value of series `key` of counter, including series removed as stale.
+    fn last_count(&mut self, key: &(Cow<'static, str>, Vec<String>)) -> Option<f64> {
+        match self.series.get(key) {
+            Some(sample) => Some(sample.value),
+            None => self.expired_counters.remove(key).map(|(value, _)| value),
+        }
+    }
+
+    /// Increment series `labels` of counter `metric` by one.
+    fn inc(&mut self, metric: &Metric, labels: &[&str]) {
+        let key = (
+            metric.name.clone(),
+            labels.iter().map(|label| label.to_string()).collect(),
+        );
+        let value = self.last_count(&key).unwrap_or_default();
+        self.set(metric, labels, value + 1.0);
+    }
+
+    /// Remove all series of `metric` with label values starting with `labels`.
+    fn remove(&mut self, metric: &Metric, labels: &[&str]) {
+        self.series.retain(|(name, values), _| {
+            *name != metric.name
+                || !values
+                    .iter()
+                    .zip(labels)
+                    .all(|(value, label)| value == label)
+        });
+    }
+
+    /// Advance series `labels` of counter `metric` to lifetime total `value`. The API
+    /// occasionally reports lower total than before (rollback), which is ignored instead of
+    /// resetting the counter, so that it is advanced again only once `value` exceeds the last one.
+    /// Negative values, which counters cannot represent, are ignored likewise.
+    fn advance(&mut self, metric: &Metric, labels: &[&str], value: f64) {
+        let key = (
+            metric.name.clone(),
+            labels.iter().map(|label| label.to_string()).collect(),
+        );
+        let last = self.last_count(&key);
+        if value.is_nan() || value < 0.0 {
+            log::debug!(
+                "ignoring invalid value {} of {}{:?}",
+                value,
+                metric.name,
+                labels
+            );
+            if let Some(last) = last {
+                self.set(metric, labels, last);
+            }
+            return;
+        }
+        match last {
+            Some(last) if value < last => {
+                log::debug!(
+                    "ignoring rollback of {}{:?} from {} to {}",
+                    metric.name,
+                    labels,
+                    last,
+                    value
                 );
+                self.set(metric, labels, last);
             }
+            _ => self.set(metric, labels, value),
         }
     }
-    Ok(())
+
+    /// Whether input `name` (e.g. `pv1`, `mppt1`) of device `device_id`, currently reporting
+    /// `values`, reported non-zero values at least once.
+    fn pv_input_used(&mut self, device_id: &str, name: &str, values: &[f64]) -> bool {
+        let key = (device_id.to_owned(), name.to_owned());
+        if values.iter().any(|value| *value != 0.0) {
+            self.used_pv_inputs.insert(key);
+            true
+        } else {
+            self.used_pv_inputs.contains(&key)
+        }
+    }
+
+    /// Remove series of stations and devices which were not updated within `grace`, e.g. because
+    /// the device was replaced or stopped reporting, and last values of counters removed more
+    /// than `kept` ago.
+    fn remove_stale_series(&mut self, grace: Duration, kept: Duration) {
+        self.expired_counters
+            .retain(|_, (_, expired_at)| expired_at.elapsed() <= kept);
+        let expired_counters = &mut self.expired_counters;
+        self.series.retain(|(name, labels), sample| {
+            if !sample.expires || sample.updated_at.elapsed() <= grace {
+                return true;
+            }
+            log::info!("removing stale series {}{:?}", name, labels);
+            if sample.kind == Kind::Counter {
+                expired_counters.insert(
+                    (name.clone(), labels.to_owned()),
+                    (sample.value, Instant::now()),
+                );
+            }
+            false
+        });
+    }
+
+    /// Render series of `family` as metric family, if there are any.
+    fn render(&self, family: &Family) -> prometheus::Result<Vec<MetricFamily>> {
+        let metric = &family.metric;
+        let opts = Opts::new(&family.name, &family.help);
+        let now = Utc::now().timestamp_millis();
+        let samples = self
+            .series
+            .iter()
+            .filter(|((name, _), _)| *name == metric.name)
+            .map(|((_, labels), sample)| {
+                let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+                let value = match metric.kind {
+                    Kind::Age => (now - sample.value as i64) as f64 / 1000.0,
+                    _ => sample.value,
+                };
+                (labels, value * family.scale)
+            });
+
+        match metric.kind {
+            Kind::Gauge | Kind::Age => {
+                let gauge = GaugeVec::new(opts, &metric.label_names())?;
+                for (labels, value) in samples {
+                    gauge.get_metric_with_label_values(&labels)?.set(value);
+                }
+                Ok(gauge.collect())
+            }
+            Kind::Counter => {
+                let counter = CounterVec::new(opts, &metric.label_names())?;
+                for (labels, value) in samples {
+                    counter.get_metric_with_label_values(&labels)?.inc_by(value);
+                }
+                Ok(counter.collect())
+            }
+        }
+    }
+}
+
+/// Metric family exported for `metric`, under its current or legacy name.
+struct Family {
+    metric: Metric,
+    name: String,
+    help: String,
+    /// Factor converting stored value to unit of the family.
+    scale: f64,
+}
+
+impl Family {
+    /// Families of all built-in and `mapped` metrics, with names prefixed with `namespace`, and
+    /// under their `legacy` names as well.
+    fn all(namespace: &str, legacy: bool, mapped: &[Metric]) -> Vec<Family> {
+        let mut families = Vec::new();
+        for metric in METRICS.iter().copied().chain(mapped) {
+            /* Metric of several mapped KPIs is exported once */
+            if families
+                .iter()
+                .any(|family: &Family| family.metric.name == metric.name)
+            {
+                continue;
+            }
+            let name = format!("{}_{}", namespace, metric.name);
+            let legacy = metric
+                .legacy
+                .as_ref()
+                .filter(|legacy_metric| legacy && legacy_metric.name != name);
+            families.push(Family {
+                metric: metric.clone(),
+                name,
+                help: metric.help.to_string(),
+                scale: 1.0,
+            });
+            if let Some(legacy) = legacy {
+                families.push(Family {
+                    metric: metric.clone(),
+                    name: legacy.name.to_owned(),
+                    help: legacy.help.to_owned(),
+                    scale: legacy.scale,
+                });
+            }
+        }
+        families
+    }
+}
+
+/// Collector rendering metrics from values stored by the last collection, at gather time.
+struct StoreCollector {
+    store: Arc<Mutex<Store>>,
+    families: Vec<Family>,
+    descs: Vec<Desc>,
 }
 
-/// Collect `day_power` metric for every station.
-async fn collect_day_power(api: &LoggedInApi) -> Result<(), api::Error> {
-    let stations = api::stations(api).await?;
+impl StoreCollector {
+    fn new(store: Arc<Mutex<Store>>, families: Vec<Family>) -> prometheus::Result<StoreCollector> {
+        let descs = families
+            .iter()
+            .map(|family| {
+                let labels = family
+                    .metric
+                    .label_names()
+                    .iter()
+                    .map(|label| label.to_string())
+                    .collect();
+                Desc::new(
+                    family.name.to_owned(),
+                    family.help.to_owned(),
+                    labels,
+                    HashMap::new(),
+                )
+            })
+            .collect::<prometheus::Result<_>>()?;
+        Ok(StoreCollector {
+            store,
+            families,
+            descs,
+        })
+    }
+}
+
+impl Collector for StoreCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let store = match self.store.lock() {
+            Ok(store) => store,
+            Err(_) => return Vec::new(),
+        };
+        self.families
+            .iter()
+            .flat_map(|family| {
+                store.render(family).unwrap_or_else(|e| {
+                    log::warn!("unable to render {}: {}", family.name, e);
+                    Vec::new()
+                })
+            })
+            .collect()
+    }
+}
+
+/// Metrics of single data source, with own registry.
+pub struct Metrics {
+    config: Config,
+    registry: Registry,
+    store: Arc<Mutex<Store>>,
+    /// Definition of metric of every entry of `config.mapping`.
+    mapped: Vec<Metric>,
+}
+
+impl Metrics {
+    pub fn new(config: Config) -> Result<Metrics, api::Error> {
+        let registry = Registry::new();
+        let store = Arc::new(Mutex::new(Store::default()));
+        let mapped: Vec<Metric> = config.mapping.iter().map(Metric::mapped).collect();
+        let families = Family::all(&config.namespace, config.legacy_names, &mapped);
+        StoreCollector::new(store.clone(), families)
+            .and_then(|collector| registry.register(Box::new(collector)))
+            .map_err(|e| {
+                log::error!("invalid metrics: {}", e);
+                api::Error::InternalError
+            })?;
+
+        #[cfg(target_os = "linux")]
+        if config.process_metrics {
+            let collector = prometheus::process_collector::ProcessCollector::for_self();
+            registry
+                .register(Box::new(collector))
+                .map_err(|_| api::Error::InternalError)?;
+        }
+
+        Ok(Metrics {
+            config,
+            registry,
+            store,
+            mapped,
+        })
+    }
+
+    /// Run `update` with exclusive access to stored values.
+    fn update(&self, update: impl FnOnce(&mut Store)) {
+        match self.store.lock() {
+            Ok(mut store) => update(&mut store),
+            Err(_) => log::error!("unable to lock metric store"),
+        }
+    }
+
+    /// Process DeviceRealKpi `device_real_kpi` of `device` installed in station `station_code`
+    /// and store them as metrics. Based on device type, different KPIs can be presented, mapped
+    /// ones for any device type.
+    fn process_device_real_kpi(
+        &self,
+        dev_real_kpi: &DeviceRealKpi,
+        station_code: &str,
+        device: Device,
+    ) {
+        /* Series are labelled by listed device, like its errors, whatever ID the KPI report */
+        let device_id = device.id.to_string();
+        let device_type_id = device.type_id.to_string();
+        let labels = [station_code, &device_id, &device_type_id];
+
+        self.update(|store| {
+            let mut set = |metric: &Metric, value: Option<f64>| {
+                if let Some(value) = value {
+                    store.set(metric, &labels, value);
+                }
+            };
+
+            set(
+                &DEVICE_DATA_AGE,
+                dev_real_kpi
+                    .collected_at
+                    .map(|collected_at| collected_at.timestamp_millis() as f64),
+            );
+
+            /* Mapped KPIs of PV strings not selected to export are skipped */
+            let mut skipped_pv_strings = Vec::new();
+            for pv in &dev_real_kpi.pv_strings {
+                let name = format!("pv{}", pv.index);
+                let values = [pv.voltage.value(), pv.current.value()];
+                let exported = match &self.config.pv_strings {
+                    PvStrings::All => true,
+                    PvStrings::Only(numbers) => numbers.contains(&pv.index),
+                    PvStrings::Auto => store.pv_input_used(&device_id, &name, &values),
+                };
+                if !exported {
+                    skipped_pv_strings.push(format!("{}_u", name));
+                    skipped_pv_strings.push(format!("{}_i", name));
+                    continue;
+                }
+                let labels = [station_code, &device_id, &device_type_id, &name];
+                let power = pv.voltage.value() * pv.current.value();
+                store.set(&DEVICE_PV_POWER, &labels, power);
+            }
+
+            for mppt in &dev_real_kpi.mppts {
+                let number = mppt.index.to_string();
+                let energy = mppt.total_energy.value();
+                /* MPPT numbers are unrelated to numbers of PV strings selected to export */
+                let used =
+                    store.pv_input_used(&device_id, &format!("mppt{}", mppt.index), &[energy]);
+                if !used && self.config.pv_strings != PvStrings::All {
+                    continue;
+                }
+                let labels = [station_code, &device_id, &device_type_id, &number];
+                store.advance(&DEVICE_MPPT_ENERGY, &labels, energy);
+            }
+
+            /* Battery and power meter connected to inverter read over Modbus */
+            if let Some(battery) = &dev_real_kpi.battery {
+                store.set(
+                    &DEVICE_BATTERY_CHARGE,
+                    &labels,
+                    battery.state_of_charge.value(),
+                );
+                store.set(
+                    &DEVICE_BATTERY_POWER,
+                    &labels,
+                    Watts::from(battery.power).value(),
+                );
+                if let Some(total_charge) = battery.total_charge {
+                    store.advance(&DEVICE_BATTERY_CHARGED, &labels, total_charge.value());
+                }
+                if let Some(total_discharge) = battery.total_discharge {
+                    store.advance(&DEVICE_BATTERY_DISCHARGED, &labels, total_discharge.value());
+                }
+            }
+            if let Some(meter) = &dev_real_kpi.meter {
+                store.set(
+                    &DEVICE_METER_POWER,
+                    &labels,
+                    Watts::from(meter.active_power).value(),
+                );
+                for phase in &meter.phases {
+                    let labels = [station_code, &device_id, &device_type_id, &phase.name];
+                    if let Some(voltage) = phase.voltage {
+                        store.set(&DEVICE_METER_VOLTAGE, &labels, voltage.value());
+                    }
+                    if let Some(current) = phase.current {
+                        store.set(&DEVICE_METER_CURRENT, &labels, current.value());
+                    }
+                }
+                if let Some(exported) = meter.exported_energy {
+                    store.advance(&DEVICE_METER_EXPORTED, &labels, exported.value());
+                }
+                if let Some(imported) = meter.imported_energy {
+                    store.advance(&DEVICE_METER_IMPORTED, &labels, imported.value());
+                }
+            }
+
+            let mapping = self.config.mapping.iter().zip(&self.mapped);
+            for (mapped, metric) in mapping.filter(|(m, _)| m.device_type == device.type_id) {
+                if skipped_pv_strings.contains(&mapped.key) {
+                    continue;
+                }
+                let value = match dev_real_kpi.data_items.get(&mapped.key) {
+                    Some(value) => value * mapped.scale,
+                    None => continue,
+                };
+                let labels: Vec<&str> = labels
+                    .iter()
+                    .copied()
+                    .chain(mapped.labels.values().map(String::as_str))
+                    .collect();
+                match mapped.metric_type {
+                    MetricType::Gauge => store.set(metric, &labels, value),
+                    MetricType::Counter => store.advance(metric, &labels, value),
+                }
+            }
+        });
+    }
+
+    /// Store StationRealKpi `kpi` as metrics. KPIs not reported by the source are skipped.
+    fn process_station_real_kpi(&self, kpi: &StationRealKpi) {
+        self.update(|store| {
+            let labels = [kpi.code.as_str()];
+            let mut set = |metric: &Metric, value: Option<f64>| {
+                if let Some(value) = value {
+                    store.set(metric, &labels, value);
+                }
+            };
+
+            set(&DAY_ENERGY, Some(kpi.day_power.value()));
+            set(&MONTH_ENERGY, kpi.month_power.map(KiloWattHours::value));
+            set(&YEAR_ENERGY, kpi.year_power.map(KiloWattHours::value));
+            set(
+                &STATION_POWER,
+                kpi.realtime_power.map(|p| Watts::from(p).value()),
+            );
+            set(
+                &CO2_REDUCTION,
+                kpi.social_contribution
+                    .as_ref()
+                    .and_then(|social| social.co2_reduction),
+            );
+
+            if let Some(total_power) = kpi.total_power {
+                store.advance(&STATION_ENERGY, &labels, total_power.value());
+            }
+        });
+    }
 
-    for station in stations {
-        let kpi = api::station_real_kpi(api, &station).await?;
+    /// Record `error` of `station` (or of its `device`, if any) in `collection` and metrics.
+    fn record_error(
+        &self,
+        collection: &mut Collection,
+        station: &Station,
+        device: Option<&Device>,
+        error: api::Error,
+    ) {
+        let kind = error.kind().as_str();
+        let now = Utc::now().timestamp_millis() as f64 / 1000.0;
 
-        match kpi.get(0) {
+        self.update(|store| match device {
+            Some(device) => {
+                let device_id = device.id.to_string();
+                let device_type_id = device.type_id.to_string();
+                let labels = [station.code.as_str(), &device_id, &device_type_id];
+                store.inc(
+                    &DEVICE_ERRORS,
+                    &[&station.code, &device_id, &device_type_id, kind],
+                );
+                store.remove(&DEVICE_LAST_ERROR_TIME, &labels);
+                store.set(
+                    &DEVICE_LAST_ERROR_TIME,
+                    &[&station.code, &device_id, &device_type_id, kind],
+                    now,
+                );
+            }
             None => {
-                log::warn!("No KPI returned for station: {}", &station.code);
+                store.inc(&STATION_ERRORS, &[&station.code, kind]);
+                store.remove(&STATION_LAST_ERROR_TIME, &[&station.code]);
+                store.set(&STATION_LAST_ERROR_TIME, &[&station.code, kind], now);
+            }
+        });
+        collection.errors.push(CollectionError {
+            station_code: station.code.to_owned(),
+            device_id: device.map(|device| device.id),
+            error,
+        });
+    }
+
+    /// Collect KPI of `device` installed in `station`, if its type is supported or mapped.
+    async fn collect_device(
+        &self,
+        collection: &mut Collection,
+        source: &dyn DataSource,
+        station: &Station,
+        device: Device,
+    ) {
+        /* Devices without any metrics are skipped, not to spend API quota on them */
+        let supported =
+            (num::FromPrimitive::from_u64(device.type_id) as Option<DeviceTypeId>).is_some();
+        let mapped = self
+            .config
+            .mapping
+            .iter()
+            .any(|m| m.device_type == device.type_id);
+        if !supported && !mapped {
+            return;
+        }
+        match source.device_real_kpi(&device).await {
+            Ok(dev_kpi_vec) => {
+                if let Some(dev_real_kpi) = dev_kpi_vec.first() {
+                    self.process_device_real_kpi(dev_real_kpi, &station.code, device.clone());
+                } else {
+                    log::error!(
+                        "No KPI returned for device {} of station {}",
+                        device.id,
+                        station.code
+                    );
+                }
+                let device_id = device.id.to_string();
+                let device_type_id = device.type_id.to_string();
+                let labels = [station.code.as_str(), &device_id, &device_type_id];
+                /* Time of sample is rarely reported by the API, so freshness is tracked this way */
+                let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+                self.update(|store| {
+                    if !dev_kpi_vec.is_empty() {
+                        store.set(&DEVICE_LAST_SUCCESS, &labels, now);
+                    }
+                    store.remove(&DEVICE_LAST_ERROR_TIME, &labels)
+                });
+                collection.collected_devices += 1;
             }
-            Some(kpi) => {
-                DAY_POWER_GAUGE
-                    .with_label_values(&[&station.code])
-                    .set(kpi.day_power);
+            Err(api::Error::UnknownDeviceType(_)) => (),
+            Err(e) => {
+                self.record_error(collection, station, Some(&device), e);
+                collection.failed_devices += 1;
+            }
+        }
+    }
+
+    /// Collect KPI of `station` and all its devices. Failure of any call is recorded, without
+    /// stopping collection of the rest.
+    async fn collect_station(
+        &self,
+        collection: &mut Collection,
+        source: &dyn DataSource,
+        station: &Station,
+    ) {
+        let mut failed = false;
+        let collected_devices = collection.collected_devices;
+        let failed_devices = collection.failed_devices;
+
+        match source.station_real_kpi(station).await {
+            Ok(kpi) => match kpi.first() {
+                None => log::debug!("No KPI returned for station: {}", &station.code),
+                Some(kpi) => self.process_station_real_kpi(kpi),
+            },
+            Err(e) => {
+                self.record_error(collection, station, None, e);
+                failed = true;
             }
         }
 
-        collect_station_devices(api, &station).await?;
+        match source.devices(station).await {
+            Ok(devices) => {
+                for device in devices {
+                    self.collect_device(collection, source, station, device)
+                        .await;
+                }
+            }
+            Err(e) => {
+                self.record_error(collection, station, None, e);
+                failed = true;
+            }
+        }
+
+        /* Station is failed also when none of its devices could be collected */
+        if collection.collected_devices == collected_devices
+            && collection.failed_devices > failed_devices
+        {
+            failed = true;
+        }
+        if failed {
+            collection.failed_stations += 1;
+        } else {
+            self.update(|store| store.remove(&STATION_LAST_ERROR_TIME, &[&station.code]));
+            collection.collected_stations += 1;
+        }
+    }
+
+    /// Collect all supported metrics from `source`, together with health of the collection
+    /// itself. Stations and devices failing to collect are skipped and reported in the result,
+    /// which is an error only if stations could not be listed at all. Series of stations and
+    /// devices not updated within `stale_after` are removed.
+    pub async fn collect(&self, source: &dyn DataSource) -> Result<Collection, api::Error> {
+        let started_at = Instant::now();
+        let mut collection = Collection::default();
+        let result = match source.stations().await {
+            Ok(stations) => {
+                for station in &stations {
+                    self.collect_station(&mut collection, source, station).await;
+                }
+                Ok(collection)
+            }
+            Err(e) => Err(e),
+        };
+        let duration = started_at.elapsed().as_secs_f64();
+
+        self.update(|store| {
+            store.set(&COLLECTION_DURATION, &[], duration);
+            if let Some(rate_limiter) = source.rate_limiter() {
+                collect_rate_limits(store, rate_limiter);
+            }
+            if let Some(stats) = source.stats() {
+                collect_stats(store, stats);
+            }
+            if let Ok(collection) = &result {
+                let stations = [
+                    ("success", collection.collected_stations),
+                    ("error", collection.failed_stations),
+                ];
+                let devices = [
+                    ("success", collection.collected_devices),
+                    ("error", collection.failed_devices),
+                ];
+                for (result, count) in stations {
+                    store.set(&COLLECTED_STATIONS, &[result], count as f64);
+                }
+                for (result, count) in devices {
+                    store.set(&COLLECTED_DEVICES, &[result], count as f64);
+                }
+            }
+            match &result {
+                Ok(collection) if collection.is_up() => {
+                    /* Last data is served during outage, so that series expire only once
+                     * collection succeeds without them */
+                    store.remove_stale_series(self.config.stale_after, EXPIRED_COUNTERS_KEPT);
+                    store.set(&UP, &[], 1.0);
+                    let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+                    store.set(&LAST_SUCCESS, &[], now);
+                }
+                _ => store.set(&UP, &[], 0.0),
+            }
+        });
+        result
     }
 
-    Ok(())
+    /// Render metrics of the registry in text format.
+    pub fn read(&self) -> Result<String, api::Error> {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        let metric_families = self.registry.gather();
+
+        encoder.encode(&metric_families, &mut buffer).unwrap();
+        String::from_utf8(buffer).or(Err(api::Error::FormatError))
+    }
 }
 
-/// Collect all supported metrics from `api`, updating Prometheus exporter registry.
-pub async fn collect(api: &Api) -> Result<(), api::Error> {
-    let logged_in_api = api::login(api).await?;
-    collect_day_power(&logged_in_api).await?;
+/// Store remaining call budget of every rate-limited API endpoint.
+fn collect_rate_limits(store: &mut Store, rate_limiter: &RateLimiter) {
+    for endpoint in endpoint::ALL {
+        if let Some(remaining) = rate_limiter.remaining(endpoint) {
+            store.set(&API_RATE_LIMIT_REMAINING, &[endpoint], remaining.into());
+        }
+    }
+}
 
-    Ok(())
+/// Store call `stats` of the data source, which are counted over lifetime of the source.
+fn collect_stats(store: &mut Store, stats: &Stats) {
+    for ((endpoint, outcome), count) in stats.requests() {
+        store.set(&API_REQUESTS, &[&endpoint, outcome], count as f64);
+    }
+    for (result, count) in stats.logins() {
+        store.set(&LOGINS, &[result], count as f64);
+    }
+    store.set(&RATE_LIMITED, &[], stats.rate_limited() as f64);
 }
 
-/// Read metrics from Prometheus exporter registry.
-pub async fn read() -> Result<String, api::Error> {
-    // Gather the metrics.
-    let mut buffer = Vec::new();
-    let encoder = TextEncoder::new();
-    let metric_families = prometheus::gather();
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_trait::async_trait;
+    use fusionsolar_rs::model::{Battery, Meter, Mppt, Phase, PvString};
+    use fusionsolar_rs::units::{Amperes, Celsius, KiloWatts, Percent, Volts};
+
+    fn config(pv_strings: PvStrings) -> Config {
+        Config {
+            stale_after: Duration::from_secs(3600),
+            pv_strings,
+            process_metrics: false,
+            namespace: String::from("fusionsolar"),
+            legacy_names: false,
+            mapping: crate::mapping::load(None).unwrap(),
+        }
+    }
+
+    fn inverter() -> Device {
+        Device { type_id: 1, id: 1 }
+    }
+
+    /// KPI of inverter with PV strings and MPPTs reporting `voltages` and `energies`.
+    fn kpi(voltages: &[f64], energies: &[f64]) -> DeviceRealKpi {
+        let data_items = voltages
+            .iter()
+            .enumerate()
+            .flat_map(|(i, voltage)| {
+                [
+                    (format!("pv{}_u", i + 1), *voltage),
+                    (format!("pv{}_i", i + 1), voltage / 100.0),
+                ]
+            })
+            .collect();
+        DeviceRealKpi {
+            id: 1,
+            pv_strings: voltages
+                .iter()
+                .enumerate()
+                .map(|(i, voltage)| PvString {
+                    index: i as u8 + 1,
+                    voltage: Volts(*voltage),
+                    current: Amperes(voltage / 100.0),
+                })
+                .collect(),
+            mppts: energies
+                .iter()
+                .enumerate()
+                .map(|(i, energy)| Mppt {
+                    index: i as u8 + 1,
+                    total_energy: KiloWattHours(*energy),
+                })
+                .collect(),
+            data_items,
+            ..Default::default()
+        }
+    }
+
+    /// Rendered samples of metric `name` (without namespace) of the inverter, without labels
+    /// identifying it.
+    fn samples(metrics: &Metrics, name: &str) -> Vec<String> {
+        let prefix = format!("fusionsolar_{}{{", name);
+        let mut samples: Vec<String> = metrics
+            .read()
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix(&prefix))
+            .map(|line| {
+                line.replace("device_id=\"1\",device_type_id=\"1\",", "")
+                    .replace(",station_code=\"StationCode\"", "")
+                    .replace("station_code=\"StationCode\",", "")
+                    .replace("station_code=\"StationCode\"", "")
+            })
+            .collect();
+        samples.sort();
+        samples
+    }
+
+    /// Value of series `labels` of `metric` in `store`.
+    fn value(store: &Store, metric: &Metric, labels: &[&str]) -> Option<f64> {
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        store
+            .series
+            .get(&(metric.name.clone(), labels))
+            .map(|sample| sample.value)
+    }
+
+    #[test]
+    fn counter_advances_to_lifetime_total() {
+        let mut store = Store::default();
+        let labels = ["StationCode", "1", "1"];
+
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 100.0);
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 101.5);
+        assert_eq!(Some(101.5), value(&store, &DEVICE_BATTERY_CHARGED, &labels));
+    }
+
+    #[test]
+    fn counter_ignores_rollback() {
+        let mut store = Store::default();
+        let labels = ["StationCode", "1", "1"];
+
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 100.0);
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 99.0);
+        assert_eq!(Some(100.0), value(&store, &DEVICE_BATTERY_CHARGED, &labels));
+
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 100.5);
+        assert_eq!(Some(100.5), value(&store, &DEVICE_BATTERY_CHARGED, &labels));
+    }
+
+    #[test]
+    fn counter_ignores_negative_values() {
+        let metrics = Metrics::new(config(PvStrings::Auto)).unwrap();
+        let labels = ["StationCode", "1", "1"];
+
+        metrics.update(|store| store.advance(&DEVICE_BATTERY_CHARGED, &labels, -1.0));
+        assert!(samples(&metrics, "device_battery_charged_energy_kwh_total").is_empty());
+
+        metrics.update(|store| {
+            store.advance(&DEVICE_BATTERY_CHARGED, &labels, 100.0);
+            store.advance(&DEVICE_BATTERY_CHARGED, &labels, -1.0);
+            store.advance(&DEVICE_BATTERY_CHARGED, &labels, f64::NAN);
+        });
+        assert_eq!(
+            vec!["} 100"],
+            samples(&metrics, "device_battery_charged_energy_kwh_total")
+        );
+    }
+
+    #[test]
+    fn counter_continues_after_expiry() {
+        let mut store = Store::default();
+        let labels = ["StationCode", "1", "1"];
+        let error_labels = ["StationCode", "api"];
+
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 100.0);
+        store.inc(&STATION_ERRORS, &error_labels);
+        store.set(&DEVICE_BATTERY_POWER, &labels, 30.0);
+        std::thread::sleep(Duration::from_millis(1));
+        store.remove_stale_series(Duration::ZERO, EXPIRED_COUNTERS_KEPT);
+        assert_eq!(None, value(&store, &DEVICE_BATTERY_CHARGED, &labels));
+        assert_eq!(None, value(&store, &DEVICE_BATTERY_POWER, &labels));
+
+        /* Rollback reported after the device returns does not reset the counter */
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 99.0);
+        assert_eq!(Some(100.0), value(&store, &DEVICE_BATTERY_CHARGED, &labels));
+        store.inc(&STATION_ERRORS, &error_labels);
+        assert_eq!(Some(2.0), value(&store, &STATION_ERRORS, &error_labels));
+    }
+
+    #[test]
+    fn expired_counters_are_kept_for_limited_time() {
+        let mut store = Store::default();
+        let labels = ["StationCode", "1", "1"];
+
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 100.0);
+        std::thread::sleep(Duration::from_millis(1));
+        store.remove_stale_series(Duration::ZERO, Duration::ZERO);
+        assert_eq!(1, store.expired_counters.len());
+        std::thread::sleep(Duration::from_millis(1));
+        store.remove_stale_series(Duration::ZERO, Duration::ZERO);
+        assert!(store.expired_counters.is_empty());
+
+        /* Device returning after that starts new counter */
+        store.advance(&DEVICE_BATTERY_CHARGED, &labels, 99.0);
+        assert_eq!(Some(99.0), value(&store, &DEVICE_BATTERY_CHARGED, &labels));
+    }
+
+    #[test]
+    fn unused_pv_strings_are_skipped() {
+        let metrics = Metrics::new(config(PvStrings::Auto)).unwrap();
+
+        metrics.process_device_real_kpi(&kpi(&[300.0, 0.0], &[]), "StationCode", inverter());
+        assert_eq!(
+            vec!["string=\"pv1\"} 300"],
+            samples(&metrics, "device_pv_voltage_volts")
+        );
+
+        /* Once used, string is exported even while reporting zeros (e.g. at night) */
+        metrics.process_device_real_kpi(&kpi(&[0.0, 0.0], &[]), "StationCode", inverter());
+        assert_eq!(
+            vec!["string=\"pv1\"} 0"],
+            samples(&metrics, "device_pv_voltage_volts")
+        );
+    }
+
+    #[test]
+    fn listed_pv_strings_are_exported() {
+        let metrics = Metrics::new(config(PvStrings::Only(vec![2]))).unwrap();
+        metrics.process_device_real_kpi(
+            &kpi(&[300.0, 0.0, 310.0], &[500.0, 0.0, 0.0]),
+            "StationCode",
+            inverter(),
+        );
+
+        assert_eq!(
+            vec!["string=\"pv2\"} 0"],
+            samples(&metrics, "device_pv_voltage_volts")
+        );
+        /* MPPTs are not selected by numbers of strings */
+        assert_eq!(
+            vec!["mppt=\"1\"} 500"],
+            samples(&metrics, "device_mppt_energy_kwh_total")
+        );
+    }
+
+    #[test]
+    fn all_pv_inputs_are_exported() {
+        let metrics = Metrics::new(config(PvStrings::All)).unwrap();
+        metrics.process_device_real_kpi(
+            &kpi(&[300.0, 0.0], &[500.0, 0.0]),
+            "StationCode",
+            inverter(),
+        );
+
+        assert_eq!(2, samples(&metrics, "device_pv_voltage_volts").len());
+        assert_eq!(
+            vec!["mppt=\"1\"} 500", "mppt=\"2\"} 0"],
+            samples(&metrics, "device_mppt_energy_kwh_total")
+        );
+    }
+
+    #[test]
+    fn battery_and_meter_are_exported() {
+        let metrics = Metrics::new(config(PvStrings::Auto)).unwrap();
+        let kpi = DeviceRealKpi {
+            id: 1,
+            battery: Some(Battery {
+                state_of_charge: Percent(85.5),
+                power: KiloWatts(-2.0),
+                total_charge: Some(KiloWattHours(1000.0)),
+                total_discharge: None,
+            }),
+            meter: Some(Meter {
+                active_power: KiloWatts(-1.5),
+                phases: vec![Phase {
+                    name: String::from("A"),
+                    voltage: Some(Volts(230.5)),
+                    current: None,
+                }],
+                exported_energy: Some(KiloWattHours(1234.56)),
+                imported_energy: None,
+            }),
+            ..Default::default()
+        };
+        metrics.process_device_real_kpi(&kpi, "StationCode", inverter());
+
+        assert_eq!(
+            vec!["} 85.5"],
+            samples(&metrics, "device_battery_state_of_charge_percent")
+        );
+        assert_eq!(
+            vec!["} -2000"],
+            samples(&metrics, "device_battery_power_watts")
+        );
+        assert_eq!(
+            vec!["} 1000"],
+            samples(&metrics, "device_battery_charged_energy_kwh_total")
+        );
+        assert!(samples(&metrics, "device_battery_discharged_energy_kwh_total").is_empty());
+        assert_eq!(
+            vec!["} -1500"],
+            samples(&metrics, "device_meter_active_power_watts")
+        );
+        assert_eq!(
+            vec!["phase=\"A\"} 230.5"],
+            samples(&metrics, "device_meter_voltage_volts")
+        );
+        assert!(samples(&metrics, "device_meter_current_amperes").is_empty());
+        assert_eq!(
+            vec!["} 1234.56"],
+            samples(&metrics, "device_meter_exported_energy_kwh_total")
+        );
+    }
+
+    #[test]
+    fn default_device_metrics_follow_mapping() {
+        let mut config = config(PvStrings::Auto);
+        for mapped in config.mapping.iter_mut() {
+            if mapped.key == "active_power" {
+                mapped.name = String::from("device_active_power_kilowatts");
+                mapped.scale = 1.0;
+            }
+        }
+        config.mapping.retain(|mapped| mapped.key != "temperature");
+        let metrics = Metrics::new(config).unwrap();
+        let kpi = DeviceRealKpi {
+            id: 1,
+            data_items: HashMap::from([
+                (String::from("active_power"), 2.5),
+                (String::from("temperature"), 30.0),
+            ]),
+            ..Default::default()
+        };
+        metrics.process_device_real_kpi(&kpi, "StationCode", inverter());
+
+        assert_eq!(
+            vec!["} 2.5"],
+            samples(&metrics, "device_active_power_kilowatts")
+        );
+        assert!(samples(&metrics, "device_active_power_watts").is_empty());
+        assert!(samples(&metrics, "device_temperature_celsius").is_empty());
+    }
+
+    #[test]
+    fn legacy_names_of_previous_release_are_exported() {
+        let mut config = config(PvStrings::Auto);
+        config.legacy_names = true;
+        let metrics = Metrics::new(config).unwrap();
+        let kpi = DeviceRealKpi {
+            id: 1,
+            active_power: Some(KiloWatts(2.5)),
+            total_energy: Some(KiloWattHours(700.0)),
+            data_items: HashMap::from([
+                (String::from("active_power"), 2.5),
+                (String::from("total_cap"), 700.0),
+            ]),
+            ..Default::default()
+        };
+        metrics.process_device_real_kpi(&kpi, "StationCode", inverter());
+
+        let names: Vec<&str> = Family::all("fusionsolar", true, &metrics.mapped)
+            .iter()
+            .filter(|family| !family.name.starts_with("fusionsolar_"))
+            .map(|family| family.metric.legacy.as_ref().unwrap().name)
+            .collect();
+        assert_eq!(
+            vec!["day_power", "device_active_power", "device_temperature"],
+            names
+        );
+
+        let rendered = metrics.read().unwrap();
+        assert!(rendered.contains(
+            "\ndevice_active_power{device_id=\"1\",device_type_id=\"1\",station_code=\"StationCode\"} 2.5\n"
+        ));
+        assert!(rendered.contains("fusionsolar_device_active_power_watts{"));
+    }
+
+    /// Mapping of KPI `key` of devices of `device_type` to metric `name`.
+    fn mapped(device_type: u64, key: &str, name: &str, metric_type: MetricType) -> MappedMetric {
+        MappedMetric {
+            device_type,
+            key: key.to_owned(),
+            name: name.to_owned(),
+            help: String::from("help"),
+            metric_type,
+            scale: 1.0,
+            labels: Default::default(),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn mapping_applies_to_any_device_type() {
+        let mut config = config(PvStrings::Auto);
+        config.mapping = vec![mapped(
+            62,
+            "run_state",
+            "dongle_connected",
+            MetricType::Gauge,
+        )];
+        let metrics = Metrics::new(config).unwrap();
+        let kpi = DeviceRealKpi {
+            id: 1,
+            data_items: HashMap::from([(String::from("run_state"), 1.0)]),
+            ..Default::default()
+        };
+        metrics.process_device_real_kpi(&kpi, "StationCode", Device { type_id: 62, id: 1 });
+
+        assert!(metrics.read().unwrap().contains(
+            "\nfusionsolar_dongle_connected{device_id=\"1\",device_type_id=\"62\",station_code=\"StationCode\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn mapped_kpis_are_scaled_and_counted() {
+        let mut config = config(PvStrings::Auto);
+        let mut power = mapped(
+            1,
+            "mppt_power",
+            "device_mppt_power_watts",
+            MetricType::Gauge,
+        );
+        power.scale = 1000.0;
+        power
+            .labels
+            .insert(String::from("source"), String::from("api"));
+        config.mapping = vec![
+            power,
+            mapped(
+                1,
+                "total_cap",
+                "device_total_kwh_total",
+                MetricType::Counter,
+            ),
+            mapped(
+                1,
+                "efficiency",
+                "device_efficiency_percent",
+                MetricType::Gauge,
+            ),
+        ];
+        let metrics = Metrics::new(config).unwrap();
+        let kpi = |power: f64, total: f64| DeviceRealKpi {
+            id: 1,
+            data_items: HashMap::from([
+                (String::from("mppt_power"), power),
+                (String::from("total_cap"), total),
+            ]),
+            ..Default::default()
+        };
+
+        metrics.process_device_real_kpi(&kpi(2.5, 700.0), "StationCode", inverter());
+        metrics.process_device_real_kpi(&kpi(2.0, 699.0), "StationCode", inverter());
+        assert_eq!(
+            vec!["source=\"api\"} 2000"],
+            samples(&metrics, "device_mppt_power_watts")
+        );
+        assert_eq!(vec!["} 700"], samples(&metrics, "device_total_kwh_total"));
+        /* Keys not reported by the device are skipped */
+        assert!(samples(&metrics, "device_efficiency_percent").is_empty());
+    }
+
+    /// Source failing to list stations, e.g. during API outage.
+    struct Unreachable;
 
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    String::from_utf8(buffer).or(Err(api::Error::FormatError))
+    #[async_trait]
+    impl DataSource for Unreachable {
+        fn name(&self) -> &'static str {
+            "unreachable"
+        }
+
+        async fn stations(&self) -> Result<Vec<Station>, api::Error> {
+            Err(api::Error::ApiError(String::from("unreachable")))
+        }
+
+        async fn station_real_kpi(&self, _: &Station) -> Result<Vec<StationRealKpi>, api::Error> {
+            Err(api::Error::ApiError(String::from("unreachable")))
+        }
+
+        async fn devices(&self, _: &Station) -> Result<Vec<Device>, api::Error> {
+            Err(api::Error::ApiError(String::from("unreachable")))
+        }
+
+        async fn device_real_kpi(&self, _: &Device) -> Result<Vec<DeviceRealKpi>, api::Error> {
+            Err(api::Error::ApiError(String::from("unreachable")))
+        }
+    }
+
+    /// Source of stations `A` (failing to read KPI) and `B` with devices `1` (failing) and `2`.
+    struct PartiallyFailing;
+
+    #[async_trait]
+    impl DataSource for PartiallyFailing {
+        fn name(&self) -> &'static str {
+            "partially failing"
+        }
+
+        async fn stations(&self) -> Result<Vec<Station>, api::Error> {
+            let station = |code: &str| Station {
+                capacity: KiloWatts(5.0),
+                name: code.to_owned(),
+                code: code.to_owned(),
+                time_zone: chrono_tz::UTC,
+            };
+            Ok(vec![station("A"), station("B")])
+        }
+
+        async fn station_real_kpi(
+            &self,
+            station: &Station,
+        ) -> Result<Vec<StationRealKpi>, api::Error> {
+            match station.code.as_str() {
+                "A" => Err(api::Error::ApiError(String::from(
+                    "response body, retry in 5s",
+                ))),
+                code => Ok(vec![StationRealKpi {
+                    code: code.to_owned(),
+                    day_power: KiloWattHours(3.0),
+                    month_power: None,
+                    year_power: None,
+                    total_power: None,
+                    realtime_power: None,
+                    social_contribution: None,
+                    collected_at: None,
+                }]),
+            }
+        }
+
+        async fn devices(&self, station: &Station) -> Result<Vec<Device>, api::Error> {
+            match station.code.as_str() {
+                "B" => Ok(vec![
+                    Device { type_id: 1, id: 1 },
+                    Device { type_id: 1, id: 2 },
+                ]),
+                _ => Ok(Vec::new()),
+            }
+        }
+
+        async fn device_real_kpi(&self, device: &Device) -> Result<Vec<DeviceRealKpi>, api::Error> {
+            match device.id {
+                1 => Err(api::Error::RateExceeded(String::from("retry in 42s"))),
+                /* KPI reported under ID other than the one of listed device */
+                id => Ok(vec![DeviceRealKpi {
+                    id: id + 100,
+                    temperature: Some(Celsius(30.0)),
+                    data_items: HashMap::from([(String::from("temperature"), 30.0)]),
+                    ..Default::default()
+                }]),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn collection_continues_past_failures() {
+        let metrics = Metrics::new(config(PvStrings::Auto)).unwrap();
+        let collection = metrics.collect(&PartiallyFailing).await.unwrap();
+
+        assert_eq!(
+            (1, 1),
+            (collection.collected_stations, collection.failed_stations)
+        );
+        assert_eq!(
+            (1, 1),
+            (collection.collected_devices, collection.failed_devices)
+        );
+        assert!(collection.is_up());
+        assert_eq!(2, collection.errors.len());
+
+        let rendered = metrics.read().unwrap();
+        assert!(rendered.contains("fusionsolar_up 1"));
+        assert!(rendered.contains("fusionsolar_station_day_energy_kwh{station_code=\"B\"} 3"));
+        assert!(rendered
+            .contains("fusionsolar_station_errors_total{kind=\"api\",station_code=\"A\"} 1"));
+        /* Messages of errors are not used as label values */
+        assert!(rendered.contains(
+            "fusionsolar_device_last_error_timestamp_seconds{device_id=\"1\",device_type_id=\"1\",kind=\"rate_limited\",station_code=\"B\"}"
+        ));
+        assert!(!rendered.contains("retry in"));
+        assert!(rendered.contains(
+            "fusionsolar_device_last_success_timestamp_seconds{device_id=\"2\",device_type_id=\"1\",station_code=\"B\"}"
+        ));
+        assert!(
+            !rendered.contains("fusionsolar_device_last_success_timestamp_seconds{device_id=\"1\"")
+        );
+        assert!(rendered.contains(
+            "fusionsolar_device_temperature_celsius{device_id=\"2\",device_type_id=\"1\",station_code=\"B\"} 30"
+        ));
+    }
+
+    #[tokio::test]
+    async fn series_are_kept_during_outage() {
+        let mut config = config(PvStrings::Auto);
+        config.stale_after = Duration::from_millis(100);
+        let metrics = Metrics::new(config).unwrap();
+        metrics.collect(&PartiallyFailing).await.unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+
+        assert!(metrics.collect(&Unreachable).await.is_err());
+        let rendered = metrics.read().unwrap();
+        assert!(rendered.contains("fusionsolar_up 0"));
+        assert!(rendered.contains("fusionsolar_station_day_energy_kwh{station_code=\"B\"} 3"));
+    }
 }
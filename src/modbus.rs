@@ -0,0 +1,500 @@
+//! Reading SUN2000 inverters locally over Modbus-TCP, without the 5 minute delay and quotas of the
+//! cloud API.
+//!
+//! Registers follow Huawei "Solar Inverter Modbus Interface Definitions". Battery and power meter
+//! registers are read if the inverter exposes them, devices not connected to it are skipped.
+
+use crate::api::Error;
+use crate::model;
+use crate::units::{
+    Amperes, Celsius, Hertz, KiloVars, KiloWattHours, KiloWatts, Percent, Volts, Watts,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_modbus::client::{tcp, Client, Context, Reader};
+use tokio_modbus::Slave;
+
+pub const DEFAULT_PORT: u16 = 502;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NUMBER_OF_PV_STRINGS: u16 = 30071;
+const PV_STRINGS: u16 = 32016;
+/// Active power, phase values, temperature and startup/shutdown time.
+const POWER: (u16, u16) = (32064, 31);
+const ENERGY: (u16, u16) = (32106, 10);
+const METER: (u16, u16) = (37100, 23);
+const BATTERY: (u16, u16) = (37760, 26);
+
+const PHASES: [&str; 3] = ["A", "B", "C"];
+const LINES: [&str; 3] = ["AB", "BC", "CA"];
+
+/// Create client of inverter at `addr` with Modbus `unit_id`.
+pub fn inverter(addr: SocketAddr, unit_id: u8) -> model::Inverter {
+    model::Inverter {
+        addr,
+        unit_id,
+        timeout: DEFAULT_TIMEOUT,
+    }
+}
+
+/// Consecutive holding registers starting at `start`.
+struct Block {
+    start: u16,
+    words: Vec<u16>,
+}
+
+impl Block {
+    fn u16(&self, addr: u16) -> Option<u16> {
+        let offset = addr.checked_sub(self.start)? as usize;
+        self.words.get(offset).copied()
+    }
+
+    fn i16(&self, addr: u16) -> Option<i16> {
+        self.u16(addr).map(|w| w as i16)
+    }
+
+    fn u32(&self, addr: u16) -> Option<u32> {
+        Some((self.u16(addr)? as u32) << 16 | self.u16(addr + 1)? as u32)
+    }
+
+    fn i32(&self, addr: u16) -> Option<i32> {
+        self.u32(addr).map(|w| w as i32)
+    }
+
+    /// Unsigned value divided by `gain`.
+    fn gained_u16(&self, addr: u16, gain: f64) -> Option<f64> {
+        self.u16(addr).map(|w| w as f64 / gain)
+    }
+
+    fn gained_i16(&self, addr: u16, gain: f64) -> Option<f64> {
+        self.i16(addr).map(|w| w as f64 / gain)
+    }
+
+    fn gained_u32(&self, addr: u16, gain: f64) -> Option<f64> {
+        self.u32(addr).map(|w| w as f64 / gain)
+    }
+
+    fn gained_i32(&self, addr: u16, gain: f64) -> Option<f64> {
+        self.i32(addr).map(|w| w as f64 / gain)
+    }
+
+    /// Time sent as epoch seconds, 0 meaning not set.
+    fn time(&self, addr: u16) -> Option<DateTime<Utc>> {
+        self.u32(addr)
+            .filter(|&secs| secs != 0)
+            .and_then(|secs| Utc.timestamp_opt(secs as i64, 0).single())
+    }
+}
+
+/// Connection to single inverter.
+struct Connection<'a> {
+    inverter: &'a model::Inverter,
+    context: Context,
+}
+
+impl Connection<'_> {
+    async fn open(inverter: &model::Inverter) -> Result<Connection<'_>, Error> {
+        let connect = tcp::connect_slave(inverter.addr, Slave(inverter.unit_id));
+        let context = timeout(inverter.timeout, connect)
+            .await
+            .map_err(|_| Error::ModbusError(format!("connection to {} timed out", inverter.addr)))?
+            .map_err(|e| Error::ModbusError(format!("connection to {}: {}", inverter.addr, e)))?;
+        Ok(Connection { inverter, context })
+    }
+
+    /// Read `count` registers at `start`. Registers not supported by the inverter (i.e. rejected
+    /// with exception) are `None`, transport errors fail.
+    async fn read(&mut self, (start, count): (u16, u16)) -> Result<Option<Block>, Error> {
+        let read = self.context.read_holding_registers(start, count);
+        let response = timeout(self.inverter.timeout, read)
+            .await
+            .map_err(|_| Error::ModbusError(format!("reading register {} timed out", start)))?
+            .map_err(|e| Error::ModbusError(format!("reading register {}: {}", start, e)))?;
+
+        match response {
+            Ok(words) => Ok(Some(Block { start, words })),
+            Err(exception) => {
+                log::debug!(
+                    "register {} of unit {} not available: {}",
+                    start,
+                    self.inverter.unit_id,
+                    exception
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn pv_strings(block: &Block, count: u16) -> Vec<model::PvString> {
+    (0..count)
+        .filter_map(|i| {
+            let addr = PV_STRINGS + 2 * i;
+            Some(model::PvString {
+                index: i as u8 + 1,
+                voltage: Volts(block.gained_i16(addr, 10.0)?),
+                current: Amperes(block.gained_i16(addr + 1, 100.0)?),
+            })
+        })
+        .collect()
+}
+
+fn phases(
+    voltage: impl Fn(usize) -> Option<f64>,
+    current: impl Fn(usize) -> Option<f64>,
+) -> Vec<model::Phase> {
+    PHASES
+        .iter()
+        .enumerate()
+        .map(|(i, name)| model::Phase {
+            name: (*name).to_owned(),
+            voltage: voltage(i).map(Volts),
+            current: current(i).map(Amperes),
+        })
+        .collect()
+}
+
+fn meter(block: &Block) -> Option<model::Meter> {
+    /* Status 0 means the meter is offline */
+    block.u16(37100).filter(|&status| status != 0)?;
+    Some(model::Meter {
+        active_power: KiloWatts::from(Watts(block.gained_i32(37113, 1.0)?)),
+        phases: phases(
+            |i| block.gained_i32(37101 + 2 * i as u16, 10.0),
+            |i| block.gained_i32(37107 + 2 * i as u16, 100.0),
+        ),
+        exported_energy: block.gained_i32(37119, 100.0).map(KiloWattHours),
+        imported_energy: block.gained_i32(37121, 100.0).map(KiloWattHours),
+    })
+}
+
+fn battery(block: &Block) -> Option<model::Battery> {
+    /* Running status 0 means the battery is offline */
+    block.u16(37762).filter(|&status| status != 0)?;
+    Some(model::Battery {
+        state_of_charge: Percent(block.gained_u16(37760, 10.0)?),
+        power: KiloWatts::from(Watts(block.gained_i32(37765, 1.0)?)),
+        total_charge: block.gained_u32(37780, 100.0).map(KiloWattHours),
+        total_discharge: block.gained_u32(37782, 100.0).map(KiloWattHours),
+    })
+}
+
+/// Device as which `inverter` is reported, identified by its unit ID.
+pub fn device(inverter: &model::Inverter) -> model::Device {
+    model::Device {
+        type_id: model::DeviceTypeId::StringInverter as u64,
+        id: inverter.unit_id as u64,
+    }
+}
+
+/// Read KPI of `inverter`.
+pub async fn device_real_kpi(inverter: &model::Inverter) -> Result<model::DeviceRealKpi, Error> {
+    let mut connection = Connection::open(inverter).await?;
+    let unsupported = || Error::ModbusError(format!("unit {} is not SUN2000", inverter.unit_id));
+
+    let power = connection.read(POWER).await?.ok_or_else(unsupported)?;
+    let strings = connection
+        .read((NUMBER_OF_PV_STRINGS, 1))
+        .await?
+        .and_then(|block| block.u16(NUMBER_OF_PV_STRINGS))
+        .unwrap_or_default()
+        .min(24);
+    let pv_strings = match strings {
+        0 => Vec::new(),
+        n => connection
+            .read((PV_STRINGS, 2 * n))
+            .await?
+            .map(|block| pv_strings(&block, n))
+            .unwrap_or_default(),
+    };
+    let energy = connection.read(ENERGY).await?;
+    let meter = connection.read(METER).await?.as_ref().and_then(meter);
+    let battery = connection.read(BATTERY).await?.as_ref().and_then(battery);
+
+    if let Err(e) = connection.context.disconnect().await {
+        log::debug!("unable to disconnect from {}: {}", inverter.addr, e);
+    }
+
+    let mut kpi = model::DeviceRealKpi {
+        id: inverter.unit_id as u64,
+        temperature: power.gained_i16(32087, 10.0).map(Celsius),
+        active_power: power.gained_i32(32080, 1000.0).map(KiloWatts),
+        collected_at: Some(Utc::now()),
+        open_time: power.time(32091),
+        close_time: power.time(32093),
+        pv_strings,
+        mppts: Vec::new(),
+        phases: phases(
+            |i| power.gained_u16(32069 + i as u16, 10.0),
+            |i| power.gained_i32(32072 + 2 * i as u16, 1000.0),
+        ),
+        line_voltages: LINES
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| {
+                Some(model::LineVoltage {
+                    name: (*name).to_owned(),
+                    voltage: Volts(power.gained_u16(32066 + i as u16, 10.0)?),
+                })
+            })
+            .collect(),
+        frequency: power.gained_u16(32085, 100.0).map(Hertz),
+        power_factor: power.gained_i16(32084, 1000.0),
+        reactive_power: power.gained_i32(32082, 1000.0).map(KiloVars),
+        day_energy: energy
+            .as_ref()
+            .and_then(|e| e.gained_u32(32114, 100.0))
+            .map(KiloWattHours),
+        total_energy: energy
+            .as_ref()
+            .and_then(|e| e.gained_u32(32106, 100.0))
+            .map(KiloWattHours),
+        battery,
+        meter,
+        data_items: HashMap::new(),
+    };
+    kpi.data_items = data_items(&kpi, &power);
+    Ok(kpi)
+}
+
+/// Values of `kpi` and registers of `power` block by `dataItemMap` keys of the cloud API with
+/// the same meaning and unit, so that metric mapping applies to both sources.
+fn data_items(kpi: &model::DeviceRealKpi, power: &Block) -> HashMap<String, f64> {
+    let mut items = HashMap::new();
+    let mut insert = |key: String, value: Option<f64>| {
+        if let Some(value) = value {
+            items.insert(key, value);
+        }
+    };
+
+    insert("temperature".into(), kpi.temperature.map(|t| t.value()));
+    insert("active_power".into(), kpi.active_power.map(|p| p.value()));
+    insert("mppt_power".into(), power.gained_i32(32064, 1000.0));
+    insert("efficiency".into(), power.gained_u16(32086, 100.0));
+    insert("elec_freq".into(), kpi.frequency.map(|f| f.value()));
+    insert("power_factor".into(), kpi.power_factor);
+    insert(
+        "reactive_power".into(),
+        kpi.reactive_power.map(|p| p.value()),
+    );
+    insert("day_cap".into(), kpi.day_energy.map(|e| e.value()));
+    insert("total_cap".into(), kpi.total_energy.map(|e| e.value()));
+    for phase in &kpi.phases {
+        let name = phase.name.to_lowercase();
+        insert(format!("{}_u", name), phase.voltage.map(|v| v.value()));
+        insert(format!("{}_i", name), phase.current.map(|i| i.value()));
+    }
+    for line in &kpi.line_voltages {
+        insert(
+            format!("{}_u", line.name.to_lowercase()),
+            Some(line.voltage.value()),
+        );
+    }
+    for pv in &kpi.pv_strings {
+        insert(format!("pv{}_u", pv.index), Some(pv.voltage.value()));
+        insert(format!("pv{}_i", pv.index), Some(pv.current.value()));
+    }
+    items
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::future;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+    use tokio_modbus::server::Service;
+    use tokio_modbus::{ExceptionCode, Request, Response};
+
+    /// Simulated inverter serving holding registers from a map.
+    struct Simulator {
+        registers: HashMap<u16, u16>,
+    }
+
+    impl Service for Simulator {
+        type Request = Request<'static>;
+        type Response = Response;
+        type Exception = ExceptionCode;
+        type Future = future::Ready<Result<Response, ExceptionCode>>;
+
+        fn call(&self, request: Request<'static>) -> Self::Future {
+            future::ready(match request {
+                Request::ReadHoldingRegisters(start, count) => (start..start + count)
+                    .map(|addr| self.registers.get(&addr).copied())
+                    .collect::<Option<Vec<_>>>()
+                    .map(Response::ReadHoldingRegisters)
+                    .ok_or(ExceptionCode::IllegalDataAddress),
+                _ => Err(ExceptionCode::IllegalFunction),
+            })
+        }
+    }
+
+    /// Serve `simulator` on local port, returning its address.
+    async fn serve(simulator: Simulator) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let simulator = Arc::new(simulator);
+
+        tokio::spawn(async move {
+            let new_service = |_| Ok(Some(simulator.clone()));
+            let on_connected = |stream, socket_addr| async move {
+                accept_tcp_connection(stream, socket_addr, new_service)
+            };
+            Server::new(listener).serve(&on_connected, |_| ()).await
+        });
+        addr
+    }
+
+    fn set(registers: &mut HashMap<u16, u16>, start: u16, count: u16, words: &[(u16, u16)]) {
+        for addr in start..start + count {
+            registers.insert(addr, 0);
+        }
+        for (addr, word) in words {
+            registers.insert(*addr, *word);
+        }
+    }
+
+    /// Registers of inverter without battery and power meter.
+    fn inverter_registers() -> HashMap<u16, u16> {
+        let mut registers = HashMap::new();
+        set(&mut registers, 30071, 1, &[(30071, 2)]);
+        set(
+            &mut registers,
+            32016,
+            4,
+            &[(32016, 3521), (32017, 512), (32018, 3498), (32019, 505)],
+        );
+        set(
+            &mut registers,
+            POWER.0,
+            POWER.1,
+            &[
+                (32066, 3986),
+                (32069, 2301),
+                (32073, 2987),
+                (32080, 0),
+                (32081, 2053),
+                (32083, 1),
+                (32084, 999),
+                (32085, 5002),
+                (32086, 9709),
+                (32087, 365),
+            ],
+        );
+        set(
+            &mut registers,
+            ENERGY.0,
+            ENERGY.1,
+            &[(32107, 10000), (32115, 311)],
+        );
+        registers
+    }
+
+    #[tokio::test]
+    async fn read_inverter() {
+        let registers = inverter_registers();
+        let addr = serve(Simulator { registers }).await;
+
+        let kpi = device_real_kpi(&inverter(addr, 1)).await.unwrap();
+
+        assert_eq!(Some(KiloWatts(2.053)), kpi.active_power);
+        assert_eq!(Some(Celsius(36.5)), kpi.temperature);
+        assert_eq!(Some(Hertz(50.02)), kpi.frequency);
+        assert_eq!(Some(KiloWattHours(3.11)), kpi.day_energy);
+        assert_eq!(Some(KiloWattHours(100.0)), kpi.total_energy);
+        assert_eq!(2, kpi.pv_strings.len());
+        assert_eq!(Volts(349.8), kpi.pv_strings[1].voltage);
+        assert_eq!(Amperes(5.12), kpi.pv_strings[0].current);
+        assert_eq!(Some(Volts(230.1)), kpi.phases[0].voltage);
+        assert_eq!(Some(Amperes(2.987)), kpi.phases[0].current);
+        assert_eq!(Volts(398.6), kpi.line_voltages[0].voltage);
+        assert_eq!(Some(0.999), kpi.power_factor);
+        assert_eq!(Some(KiloVars(0.001)), kpi.reactive_power);
+        assert_eq!(None, kpi.open_time);
+        assert_eq!(None, kpi.battery);
+        assert_eq!(None, kpi.meter);
+        assert_eq!(Some(&97.09), kpi.data_items.get("efficiency"));
+        assert_eq!(Some(&2.053), kpi.data_items.get("active_power"));
+        assert_eq!(Some(&349.8), kpi.data_items.get("pv2_u"));
+        assert_eq!(Some(&230.1), kpi.data_items.get("a_u"));
+    }
+
+    #[tokio::test]
+    async fn read_battery_and_meter() {
+        let mut registers = inverter_registers();
+        set(
+            &mut registers,
+            METER.0,
+            METER.1,
+            &[
+                (37100, 1),
+                (37102, 2305),
+                (37108, 512),
+                (37113, 0xffff),
+                (37114, 0xfa24),
+                (37119, 1),
+                (37120, 0xe240),
+                (37122, 5000),
+            ],
+        );
+        set(
+            &mut registers,
+            BATTERY.0,
+            BATTERY.1,
+            &[
+                (37760, 855),
+                (37762, 2),
+                (37766, 2000),
+                (37780, 1),
+                (37781, 0x86a0),
+                (37782, 1),
+                (37783, 0x5f90),
+            ],
+        );
+        let addr = serve(Simulator { registers }).await;
+
+        let kpi = device_real_kpi(&inverter(addr, 1)).await.unwrap();
+
+        let meter = kpi.meter.unwrap();
+        assert_eq!(KiloWatts(-1.5), meter.active_power);
+        assert_eq!(Some(Volts(230.5)), meter.phases[0].voltage);
+        assert_eq!(Some(Amperes(5.12)), meter.phases[0].current);
+        assert_eq!(Some(Volts(0.0)), meter.phases[2].voltage);
+        assert_eq!(Some(KiloWattHours(1234.56)), meter.exported_energy);
+        assert_eq!(Some(KiloWattHours(50.0)), meter.imported_energy);
+        let battery = kpi.battery.unwrap();
+        assert_eq!(Percent(85.5), battery.state_of_charge);
+        assert_eq!(KiloWatts(2.0), battery.power);
+        assert_eq!(Some(KiloWattHours(1000.0)), battery.total_charge);
+        assert_eq!(Some(KiloWattHours(900.0)), battery.total_discharge);
+    }
+
+    #[tokio::test]
+    async fn offline_battery_and_meter_are_skipped() {
+        /* Registers are readable, but report status offline */
+        let mut registers = inverter_registers();
+        set(&mut registers, METER.0, METER.1, &[(37113, 100)]);
+        set(&mut registers, BATTERY.0, BATTERY.1, &[(37760, 855)]);
+        let addr = serve(Simulator { registers }).await;
+
+        let kpi = device_real_kpi(&inverter(addr, 1)).await.unwrap();
+
+        assert_eq!(None, kpi.meter);
+        assert_eq!(None, kpi.battery);
+    }
+
+    #[tokio::test]
+    async fn unreachable_inverter() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let error = device_real_kpi(&inverter(addr, 1)).await.unwrap_err();
+        assert!(matches!(error, Error::ModbusError(_)));
+    }
+}
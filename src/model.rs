@@ -1,45 +1,361 @@
+#[cfg(feature = "client")]
+use crate::api::{cassette, RateLimiter, Stats};
+use crate::units::{Amperes, Celsius, Hertz, KiloVars, KiloWattHours, KiloWatts, Percent, Volts};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use num_derive::FromPrimitive;
-
-type KWh = f64;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "client")]
+use std::sync::{Arc, Mutex};
 
 pub type UnsupportedDeviceTypeId = u64;
 
-#[derive(Debug, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, Serialize, Deserialize)]
 pub enum DeviceTypeId {
     StringInverter = 1,
 }
 
+/// Local time zones of stations, which are not reported by the API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeZones {
+    /// Time zone of stations without explicit configuration.
+    pub default: Tz,
+    /// Time zone for every configured station code.
+    pub stations: HashMap<String, Tz>,
+}
+
+impl TimeZones {
+    /// Time zone of station `code`.
+    pub fn of(&self, code: &str) -> Tz {
+        self.stations.get(code).copied().unwrap_or(self.default)
+    }
+}
+
+impl Default for TimeZones {
+    fn default() -> TimeZones {
+        TimeZones {
+            default: Tz::UTC,
+            stations: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
 #[derive(Debug, Clone)]
 pub struct Api {
-    pub api_url: String,
+    /// API URL overriding region discovery.
+    pub api_url: Option<String>,
     pub username: String,
     pub password: String,
+    /// API URL of region discovered during login, shared between clones.
+    pub discovered_api_url: Arc<Mutex<Option<String>>>,
+    /// API URLs not tried yet by discovery interrupted by exhausted login quota, shared between
+    /// clones.
+    pub pending_api_urls: Arc<Mutex<Vec<String>>>,
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Counters of calls, shared between clones.
+    pub stats: Arc<Stats>,
+    pub time_zones: TimeZones,
+    pub cassette: cassette::Mode,
 }
 
+#[cfg(feature = "client")]
 pub struct LoggedInApi {
     pub api_url: String,
     pub xsrf_token: String,
     pub client: reqwest::Client,
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Counters of calls, shared between clones.
+    pub stats: Arc<Stats>,
+    pub time_zones: TimeZones,
+    pub cassette: cassette::Mode,
+}
+
+/// Public kiosk (share link) of single station, usable without API credentials.
+#[cfg(feature = "client")]
+#[derive(Debug, Clone)]
+pub struct Kiosk {
+    /// URL of kiosk data, to which the token is appended.
+    pub kiosk_url: String,
+    pub token: String,
+    pub client: reqwest::Client,
+    pub time_zones: TimeZones,
 }
 
+/// SUN2000 inverter reachable over Modbus-TCP, either directly or through SDongle.
+#[cfg(feature = "modbus")]
+#[derive(Debug, Clone)]
+pub struct Inverter {
+    pub addr: std::net::SocketAddr,
+    /// Modbus unit ID of the inverter (`0` when connected directly, `1` and up behind SDongle).
+    pub unit_id: u8,
+    /// Timeout of connection and every request.
+    pub timeout: std::time::Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Station {
-    pub capacity: KWh,
+    /// Installed peak power (kWp).
+    pub capacity: KiloWatts,
     pub name: String,
     pub code: String,
+    /// Local time zone of the station.
+    pub time_zone: Tz,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Device {
     pub type_id: u64,
     pub id: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct StationRealKpi {
     pub code: String,
-    pub day_power: KWh,
+    /// Energy generated in current day.
+    pub day_power: KiloWattHours,
+    /// Energy generated in current month.
+    pub month_power: Option<KiloWattHours>,
+    /// Energy generated in current year (reported by kiosk only).
+    pub year_power: Option<KiloWattHours>,
+    /// Energy generated since the station was connected.
+    pub total_power: Option<KiloWattHours>,
+    /// Current output power (reported by kiosk only).
+    pub realtime_power: Option<KiloWatts>,
+    pub social_contribution: Option<SocialContribution>,
+    /// Time at which the API reported the KPI.
+    pub collected_at: Option<DateTime<Utc>>,
+}
+
+/// Environmental benefits of the station since it was connected, as reported by kiosk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SocialContribution {
+    /// CO2 emissions avoided (in tonnes).
+    pub co2_reduction: Option<f64>,
+    /// Standard coal saved (in tonnes).
+    pub standard_coal_savings: Option<f64>,
+    /// Equivalent number of trees planted.
+    pub equivalent_tree_planting: Option<f64>,
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DeviceRealKpi {
     pub id: u64,
-    pub temperature: Option<f64>,
-    pub active_power: Option<f64>,
+    pub temperature: Option<Celsius>,
+    pub active_power: Option<KiloWatts>,
+    /// Time at which the sample was collected from the device, if the source reports it.
+    pub collected_at: Option<DateTime<Utc>>,
+    /// Time at which the device started up in current day.
+    pub open_time: Option<DateTime<Utc>>,
+    /// Time at which the device shut down last time.
+    pub close_time: Option<DateTime<Utc>>,
+    /// PV string inputs reported by the device, in order of their numbers (including unused
+    /// ones).
+    #[serde(default)]
+    pub pv_strings: Vec<PvString>,
+    /// Lifetime energy of every MPPT reported by the device.
+    #[serde(default)]
+    pub mppts: Vec<Mppt>,
+    /// AC output per phase (only `A` for single-phase devices).
+    #[serde(default)]
+    pub phases: Vec<Phase>,
+    /// AC voltages between pairs of phases.
+    #[serde(default)]
+    pub line_voltages: Vec<LineVoltage>,
+    #[serde(default)]
+    pub frequency: Option<Hertz>,
+    #[serde(default)]
+    pub power_factor: Option<f64>,
+    #[serde(default)]
+    pub reactive_power: Option<KiloVars>,
+    /// Energy generated in current day.
+    #[serde(default)]
+    pub day_energy: Option<KiloWattHours>,
+    /// Energy generated over lifetime of the device.
+    #[serde(default)]
+    pub total_energy: Option<KiloWattHours>,
+    /// Battery connected to the device, if any.
+    #[serde(default)]
+    pub battery: Option<Battery>,
+    /// Grid power meter connected to the device, if any.
+    #[serde(default)]
+    pub meter: Option<Meter>,
+    /// Numeric KPIs by key, as reported by the API (e.g. `efficiency`), including those parsed
+    /// above. Other sources report them under the same keys. The only KPIs of device types not
+    /// parsed otherwise.
+    #[serde(default)]
+    pub data_items: HashMap<String, f64>,
+}
+
+/// DC input of single PV string.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PvString {
+    /// Number of the input, starting at 1.
+    pub index: u8,
+    pub voltage: Volts,
+    pub current: Amperes,
+}
+
+/// Maximum power point tracker, combining one or more PV strings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mppt {
+    /// Number of the tracker, starting at 1.
+    pub index: u8,
+    pub total_energy: KiloWattHours,
+}
+
+/// Values of single AC phase (`A`, `B` or `C`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Phase {
+    pub name: String,
+    pub voltage: Option<Volts>,
+    pub current: Option<Amperes>,
+}
+
+/// Voltage between pair of AC phases (`AB`, `BC` or `CA`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineVoltage {
+    pub name: String,
+    pub voltage: Volts,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Battery {
+    pub state_of_charge: Percent,
+    /// Charging power, negative while discharging.
+    pub power: KiloWatts,
+    pub total_charge: Option<KiloWattHours>,
+    pub total_discharge: Option<KiloWattHours>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Meter {
+    /// Power fed into the grid, negative while drawing from it.
+    pub active_power: KiloWatts,
+    pub phases: Vec<Phase>,
+    pub exported_energy: Option<KiloWattHours>,
+    pub imported_energy: Option<KiloWattHours>,
+}
+
+/// Time period covered by single historical KPI sample.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Granularity {
+    Hour,
+    Day,
+    Month,
+    Year,
+}
+
+/// Historical KPI of station, covering period of given `Granularity` starting at `collected_at`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StationKpiHistory {
+    pub code: String,
+    pub collected_at: Option<DateTime<Utc>>,
+    /// Energy generated by inverters.
+    pub inverter_power: Option<KiloWattHours>,
+    /// Energy fed into the grid.
+    pub ongrid_power: Option<KiloWattHours>,
+    /// Energy consumed.
+    pub use_power: Option<KiloWattHours>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alarm {
+    pub station_code: String,
+    pub id: u64,
+    pub name: String,
+    pub cause: Option<String>,
+    pub repair_suggestion: Option<String>,
+    pub device_name: Option<String>,
+    pub esn_code: Option<String>,
+    pub device_type_id: Option<u64>,
+    /// Severity, from 1 (critical) to 4 (warning).
+    pub level: u64,
+    pub status: u64,
+    pub raised_at: Option<DateTime<Utc>>,
+}
+
+/// Device together with its KPI, if device type is supported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSnapshot {
+    pub device: Device,
+    pub kpi: Option<DeviceRealKpi>,
+}
+
+/// Station together with its KPI and all installed devices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StationSnapshot {
+    pub station: Station,
+    pub kpi: Option<StationRealKpi>,
+    pub devices: Vec<DeviceSnapshot>,
+}
+
+/// Complete state of all stations of the account at `collected_at`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub collected_at: DateTime<Utc>,
+    pub stations: Vec<StationSnapshot>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    #[test]
+    fn serialize_camel_case() {
+        let kpi = DeviceRealKpi {
+            id: 1000000011111111,
+            temperature: Some(Celsius(36.5)),
+            active_power: None,
+            collected_at: Utc.timestamp_millis_opt(1635758600700).single(),
+            open_time: None,
+            close_time: None,
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&kpi).unwrap();
+
+        assert_eq!(
+            json!({
+                "id": 1000000011111111u64,
+                "temperature": 36.5,
+                "activePower": null,
+                "collectedAt": "2021-11-01T09:23:20.700Z",
+                "openTime": null,
+                "closeTime": null,
+                "pvStrings": [],
+                "mppts": [],
+                "phases": [],
+                "lineVoltages": [],
+                "frequency": null,
+                "powerFactor": null,
+                "reactivePower": null,
+                "dayEnergy": null,
+                "totalEnergy": null,
+                "battery": null,
+                "meter": null,
+                "dataItems": {},
+            }),
+            value
+        );
+        assert_eq!(kpi, serde_json::from_value(value).unwrap());
+    }
 }
@@ -0,0 +1,206 @@
+use super::DataSource;
+use crate::api::{self, Error, RateLimiter, Stats};
+use crate::model;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+/// Run `$call` with logged in `$api`, logging in first if there is no session yet. Session is
+/// dropped once it expires, so the next call logs in again.
+macro_rules! with_session {
+    ($source:expr, |$api:ident| $call:expr) => {{
+        let mut session = $source.session.lock().await;
+        if session.is_none() {
+            *session = Some(api::login(&$source.api).await?);
+        }
+        let result = match session.as_ref() {
+            Some($api) => $call.await,
+            None => Err(Error::InternalError),
+        };
+        if let Err(Error::SessionExpired(_)) = result {
+            log::warn!("API session expired, will log in again");
+            *session = None;
+        }
+        result
+    }};
+}
+
+/// Northbound API, with session reused across calls. Replays cassette if `api.cassette` is set
+/// to replay.
+pub struct CloudSource {
+    api: model::Api,
+    session: Mutex<Option<model::LoggedInApi>>,
+}
+
+impl CloudSource {
+    pub fn new(api: model::Api) -> CloudSource {
+        CloudSource {
+            api,
+            session: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl DataSource for CloudSource {
+    fn name(&self) -> &'static str {
+        match self.api.cassette {
+            api::cassette::Mode::Replay(_) => "replay",
+            _ => "cloud",
+        }
+    }
+
+    async fn stations(&self) -> Result<Vec<model::Station>, Error> {
+        with_session!(self, |api| api::stations(api))
+    }
+
+    async fn station_real_kpi(
+        &self,
+        station: &model::Station,
+    ) -> Result<Vec<model::StationRealKpi>, Error> {
+        with_session!(self, |api| api::station_real_kpi(api, station))
+    }
+
+    async fn devices(&self, station: &model::Station) -> Result<Vec<model::Device>, Error> {
+        with_session!(self, |api| api::devices(api, station))
+    }
+
+    async fn device_real_kpi(
+        &self,
+        device: &model::Device,
+    ) -> Result<Vec<model::DeviceRealKpi>, Error> {
+        with_session!(self, |api| api::device_real_kpi(api, device))
+    }
+
+    async fn station_kpi_history(
+        &self,
+        station: &model::Station,
+        granularity: model::Granularity,
+        collect_time: DateTime<Utc>,
+    ) -> Result<Vec<model::StationKpiHistory>, Error> {
+        with_session!(self, |api| api::station_kpi_history(
+            api,
+            station,
+            granularity,
+            collect_time
+        ))
+    }
+
+    async fn alarms(
+        &self,
+        station: &model::Station,
+        begin: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<model::Alarm>, Error> {
+        with_session!(self, |api| api::alarms(api, station, begin, end))
+    }
+
+    async fn dump_devices(&self) -> Result<HashMap<u64, Value>, Error> {
+        with_session!(self, |api| api::dump_devices(api))
+    }
+
+    /// Close API session once in-flight calls (holding the session lock) finish.
+    async fn close(&self) -> Result<(), Error> {
+        match self.session.lock().await.take() {
+            Some(api) => api::logout(api).await,
+            None => Ok(()),
+        }
+    }
+
+    fn rate_limiter(&self) -> Option<&RateLimiter> {
+        Some(&self.api.rate_limiter)
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        Some(&self.api.stats)
+    }
+}
+
+/// Established API session, without logging in again once it expires. Replays cassette if
+/// `cassette` is set to replay.
+#[async_trait]
+impl DataSource for model::LoggedInApi {
+    fn name(&self) -> &'static str {
+        match self.cassette {
+            api::cassette::Mode::Replay(_) => "replay",
+            _ => "cloud",
+        }
+    }
+
+    async fn stations(&self) -> Result<Vec<model::Station>, Error> {
+        api::stations(self).await
+    }
+
+    async fn station_real_kpi(
+        &self,
+        station: &model::Station,
+    ) -> Result<Vec<model::StationRealKpi>, Error> {
+        api::station_real_kpi(self, station).await
+    }
+
+    async fn devices(&self, station: &model::Station) -> Result<Vec<model::Device>, Error> {
+        api::devices(self, station).await
+    }
+
+    async fn device_real_kpi(
+        &self,
+        device: &model::Device,
+    ) -> Result<Vec<model::DeviceRealKpi>, Error> {
+        api::device_real_kpi(self, device).await
+    }
+
+    async fn station_kpi_history(
+        &self,
+        station: &model::Station,
+        granularity: model::Granularity,
+        collect_time: DateTime<Utc>,
+    ) -> Result<Vec<model::StationKpiHistory>, Error> {
+        api::station_kpi_history(self, station, granularity, collect_time).await
+    }
+
+    async fn alarms(
+        &self,
+        station: &model::Station,
+        begin: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<model::Alarm>, Error> {
+        api::alarms(self, station, begin, end).await
+    }
+
+    async fn dump_devices(&self) -> Result<HashMap<u64, Value>, Error> {
+        api::dump_devices(self).await
+    }
+
+    fn rate_limiter(&self) -> Option<&RateLimiter> {
+        Some(&self.rate_limiter)
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        Some(&self.stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::cassette::{Mode, Player};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn replay_snapshot() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/cassette.jsonl");
+
+        let mut api = api::api(None, String::new(), String::new());
+        api.cassette = Mode::Replay(Arc::new(Player::open(path).unwrap()));
+        let source = CloudSource::new(api);
+
+        let snapshot = super::super::snapshot(&source).await.unwrap();
+        assert_eq!("replay", source.name());
+        assert_eq!("STATION-1", snapshot.stations[0].station.code);
+        assert_eq!(2, snapshot.stations[0].devices.len());
+        source.close().await.unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use super::DataSource;
+use crate::api::{kiosk, Error};
+use crate::model;
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// Kiosk share link of single station, without any devices.
+pub struct KioskSource {
+    kiosk: model::Kiosk,
+    /// Station read by `stations`, served to following `station_real_kpi`, so that the kiosk is
+    /// read once per collection.
+    fetched: Mutex<Option<model::StationSnapshot>>,
+}
+
+impl KioskSource {
+    pub fn new(kiosk: model::Kiosk) -> KioskSource {
+        KioskSource {
+            kiosk,
+            fetched: Mutex::new(None),
+        }
+    }
+
+    async fn station(&self) -> Result<model::StationSnapshot, Error> {
+        kiosk::snapshot(&self.kiosk)
+            .await?
+            .stations
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::ApiError(String::from("Kiosk reported no station")))
+    }
+}
+
+#[async_trait]
+impl DataSource for KioskSource {
+    fn name(&self) -> &'static str {
+        "kiosk"
+    }
+
+    async fn stations(&self) -> Result<Vec<model::Station>, Error> {
+        let station = self.station().await?;
+        let listed = station.station.clone();
+        *self.fetched.lock().or(Err(Error::InternalError))? = Some(station);
+        Ok(vec![listed])
+    }
+
+    async fn station_real_kpi(
+        &self,
+        _station: &model::Station,
+    ) -> Result<Vec<model::StationRealKpi>, Error> {
+        let fetched = self.fetched.lock().or(Err(Error::InternalError))?.take();
+        let station = match fetched {
+            Some(station) => station,
+            None => self.station().await?,
+        };
+        Ok(station.kpi.into_iter().collect())
+    }
+
+    async fn devices(&self, _station: &model::Station) -> Result<Vec<model::Device>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn device_real_kpi(
+        &self,
+        _device: &model::Device,
+    ) -> Result<Vec<model::DeviceRealKpi>, Error> {
+        Err(Error::NotSupported(String::from("kiosk devices")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn kiosk_is_read_once_per_collection() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/test/kiosk.json");
+        let body = std::fs::read_to_string(path).unwrap();
+        let (url, requests) = crate::api::test::serve("", &body).await;
+        let source = KioskSource::new(kiosk::kiosk(Some(url), String::from("token")));
+
+        let snapshot = super::super::snapshot(&source).await.unwrap();
+        assert_eq!("NE=12345678", snapshot.stations[0].station.code);
+        assert!(snapshot.stations[0].kpi.is_some());
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        super::super::snapshot(&source).await.unwrap();
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+    }
+}
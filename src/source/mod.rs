@@ -0,0 +1,111 @@
+//! Data sources the exporter can collect from, behind a common `DataSource` trait.
+//!
+//! Besides the Northbound API (`cloud`, also replaying recorded cassettes), station data can be
+//! read from kiosk share link (`kiosk`) or from inverters over Modbus-TCP (`modbus`). Calls not
+//! supported by the source fail with `Error::NotSupported`.
+
+pub mod cloud;
+pub mod kiosk;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+
+use crate::api::{Error, RateLimiter, Stats};
+use crate::model;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub use cloud::CloudSource;
+pub use kiosk::KioskSource;
+#[cfg(feature = "modbus")]
+pub use modbus::ModbusSource;
+
+#[async_trait]
+pub trait DataSource: Send + Sync {
+    /// Short name of the source, e.g. for logging.
+    fn name(&self) -> &'static str;
+
+    async fn stations(&self) -> Result<Vec<model::Station>, Error>;
+
+    async fn station_real_kpi(
+        &self,
+        station: &model::Station,
+    ) -> Result<Vec<model::StationRealKpi>, Error>;
+
+    async fn devices(&self, station: &model::Station) -> Result<Vec<model::Device>, Error>;
+
+    async fn device_real_kpi(
+        &self,
+        device: &model::Device,
+    ) -> Result<Vec<model::DeviceRealKpi>, Error>;
+
+    async fn station_kpi_history(
+        &self,
+        _station: &model::Station,
+        _granularity: model::Granularity,
+        _collect_time: DateTime<Utc>,
+    ) -> Result<Vec<model::StationKpiHistory>, Error> {
+        Err(Error::NotSupported(format!("{} KPI history", self.name())))
+    }
+
+    async fn alarms(
+        &self,
+        _station: &model::Station,
+        _begin: DateTime<Utc>,
+        _end: DateTime<Utc>,
+    ) -> Result<Vec<model::Alarm>, Error> {
+        Err(Error::NotSupported(format!("{} alarms", self.name())))
+    }
+
+    /// Raw KPI of every device type, see `api::dump_devices`.
+    async fn dump_devices(&self) -> Result<HashMap<u64, Value>, Error> {
+        Err(Error::NotSupported(format!("{} device dump", self.name())))
+    }
+
+    /// Release resources held by the source (e.g. close API session).
+    async fn close(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Client-side quotas of the source, if it has any.
+    fn rate_limiter(&self) -> Option<&RateLimiter> {
+        None
+    }
+
+    /// Counters of calls made by the source, if it keeps them.
+    fn stats(&self) -> Option<&Stats> {
+        None
+    }
+}
+
+/// Collect snapshot of all stations of `source` and their devices, including KPI of devices the
+/// source reads.
+pub async fn snapshot(source: &dyn DataSource) -> Result<model::Snapshot, Error> {
+    let mut snapshot = model::Snapshot {
+        collected_at: Utc::now(),
+        stations: Vec::new(),
+    };
+
+    for station in source.stations().await? {
+        let kpi = source.station_real_kpi(&station).await?.into_iter().next();
+        let mut station_devices = Vec::new();
+
+        for device in source.devices(&station).await? {
+            let kpi = match source.device_real_kpi(&device).await {
+                Ok(kpi) => kpi.into_iter().next(),
+                Err(Error::UnknownDeviceType(_)) => None,
+                Err(e) => return Err(e),
+            };
+            station_devices.push(model::DeviceSnapshot { device, kpi });
+        }
+
+        snapshot.stations.push(model::StationSnapshot {
+            station,
+            kpi,
+            devices: station_devices,
+        });
+    }
+
+    Ok(snapshot)
+}
@@ -0,0 +1,56 @@
+use super::DataSource;
+use crate::api::Error;
+use crate::modbus;
+use crate::model;
+use async_trait::async_trait;
+
+/// Inverters read over Modbus-TCP, reported as devices of single station.
+pub struct ModbusSource {
+    station: model::Station,
+    inverters: Vec<model::Inverter>,
+}
+
+impl ModbusSource {
+    pub fn new(station: model::Station, inverters: Vec<model::Inverter>) -> ModbusSource {
+        ModbusSource { station, inverters }
+    }
+}
+
+#[async_trait]
+impl DataSource for ModbusSource {
+    fn name(&self) -> &'static str {
+        "modbus"
+    }
+
+    async fn stations(&self) -> Result<Vec<model::Station>, Error> {
+        Ok(vec![self.station.clone()])
+    }
+
+    /// Station KPI are not available locally.
+    async fn station_real_kpi(
+        &self,
+        _station: &model::Station,
+    ) -> Result<Vec<model::StationRealKpi>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn devices(&self, station: &model::Station) -> Result<Vec<model::Device>, Error> {
+        match station.code == self.station.code {
+            true => Ok(self.inverters.iter().map(modbus::device).collect()),
+            false => Ok(Vec::new()),
+        }
+    }
+
+    async fn device_real_kpi(
+        &self,
+        device: &model::Device,
+    ) -> Result<Vec<model::DeviceRealKpi>, Error> {
+        let inverter = self
+            .inverters
+            .iter()
+            .find(|inverter| modbus::device(inverter) == *device)
+            .ok_or(Error::UnknownDeviceType(device.type_id))?;
+
+        Ok(vec![modbus::device_real_kpi(inverter).await?])
+    }
+}
@@ -0,0 +1,140 @@
+//! Physical units of values reported by devices.
+//!
+//! Every unit is a newtype over `f64`, serialized transparently as a plain number, with
+//! conversions between multiples of the same quantity.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+macro_rules! unit {
+    ($(#[$meta:meta])* $name:ident, $symbol:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(pub f64);
+
+        impl $name {
+            /// Numeric value expressed in this unit.
+            pub fn value(self) -> f64 {
+                self.0
+            }
+        }
+
+        impl From<$name> for f64 {
+            fn from(value: $name) -> f64 {
+                value.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{} {}", self.0, $symbol)
+            }
+        }
+    };
+}
+
+/// Defines conversion `$from` -> `$to` by multiplying with `$factor`, and the inverse one.
+macro_rules! conversion {
+    ($from:ident, $to:ident, $factor:expr) => {
+        impl From<$from> for $to {
+            fn from(value: $from) -> $to {
+                $to(value.0 * $factor)
+            }
+        }
+
+        impl From<$to> for $from {
+            fn from(value: $to) -> $from {
+                $from(value.0 / $factor)
+            }
+        }
+    };
+}
+
+unit!(
+    /// Power in watts.
+    Watts,
+    "W"
+);
+unit!(
+    /// Power in kilowatts.
+    KiloWatts,
+    "kW"
+);
+unit!(
+    /// Power in megawatts.
+    MegaWatts,
+    "MW"
+);
+unit!(
+    /// Energy in watt-hours.
+    WattHours,
+    "Wh"
+);
+unit!(
+    /// Energy in kilowatt-hours.
+    KiloWattHours,
+    "kWh"
+);
+unit!(
+    /// Energy in megawatt-hours.
+    MegaWattHours,
+    "MWh"
+);
+unit!(
+    /// Reactive power in kilovolt-amperes reactive.
+    KiloVars,
+    "kvar"
+);
+unit!(
+    /// Electric potential in volts.
+    Volts,
+    "V"
+);
+unit!(
+    /// Electric current in amperes.
+    Amperes,
+    "A"
+);
+unit!(
+    /// Frequency in hertz.
+    Hertz,
+    "Hz"
+);
+unit!(
+    /// Temperature in degrees Celsius.
+    Celsius,
+    "°C"
+);
+unit!(
+    /// Ratio in percent.
+    Percent,
+    "%"
+);
+
+conversion!(KiloWatts, Watts, 1000.0);
+conversion!(MegaWatts, KiloWatts, 1000.0);
+conversion!(MegaWatts, Watts, 1_000_000.0);
+conversion!(KiloWattHours, WattHours, 1000.0);
+conversion!(MegaWattHours, KiloWattHours, 1000.0);
+conversion!(MegaWattHours, WattHours, 1_000_000.0);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(KiloWatts(5.0), MegaWatts(0.005).into());
+        assert_eq!(Watts(2053.0), KiloWatts(2.053).into());
+        assert_eq!(KiloWatts(0.5), Watts(500.0).into());
+        assert_eq!(KiloWattHours(1500.0), MegaWattHours(1.5).into());
+        assert_eq!(MegaWattHours(0.25), KiloWattHours(250.0).into());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!("5 kW", KiloWatts(5.0).to_string());
+        assert_eq!("36.5 °C", Celsius(36.5).to_string());
+    }
+}